@@ -1,12 +1,22 @@
+use std::borrow::Cow;
 use std::fmt::{Debug, Error, Formatter};
+use std::ops::Deref;
+use crate::field_path::FieldPath;
 
 // pub type Search = (Vec<Box<SearchTerm>>, Vec<Box<Transform>>, Box<Option<Sort>>);
 pub type Search<'input> = (Vec<SearchTerm<'input>>, Vec<Transform<'input>>, Option<Sort<'input>>);
 
+// a string of the query, unescaped, and where it was written so errors can point at it
+#[derive(Clone)]
+pub struct Literal<'input> {
+    pub value: Cow<'input, str>,
+    pub span: (usize, usize),
+}
+
 #[derive(PartialEq)]
 pub enum SearchTerm<'input> {
-    Include(&'input str),
-    Exclude(&'input str),
+    Include(Cow<'input, str>),
+    Exclude(Cow<'input, str>),
     Any(),
     // Error,
 }
@@ -14,8 +24,13 @@ pub enum SearchTerm<'input> {
 #[derive(PartialEq)]
 pub enum Transform<'input> {
     Aggregate(Aggregation<'input>),
-    Filter { field: &'input str, comparison: Comparison, value: &'input str},
-    Parse { field: &'input str, parser: &'input str, bindings: Vec<&'input str>},
+    Filter { field: FieldPath<'input>, comparison: Comparison, value: Literal<'input>},
+    Parse { field: FieldPath<'input>, parser: Literal<'input>, bindings: Vec<Binding<'input>>},
+    Cast { field: FieldPath<'input>, to: ValueType },
+    ParseTime { field: FieldPath<'input>, format: Option<Literal<'input>>, target: Option<FieldPath<'input>> },
+    FormatTime { field: FieldPath<'input>, format: Literal<'input>, zone: Option<Literal<'input>>, target: Option<FieldPath<'input>> },
+    TimeDiff { from: FieldPath<'input>, to: FieldPath<'input>, target: FieldPath<'input> },
+    ConvertTz { field: FieldPath<'input>, zone: Literal<'input>, target: Option<FieldPath<'input>> },
     // Error,
 }
 
@@ -24,24 +39,91 @@ pub enum Comparison {
     Ne,
     Eq,
     Match,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// a `parse` capture, optionally converted on the way in: `status:int`
+#[derive(PartialEq)]
+pub struct Binding<'input> {
+    pub field: FieldPath<'input>,
+    pub cast: Option<ValueType>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Int,
+    Float,
+    Bool,
+    Timestamp,
+}
+
+impl ValueType {
+    pub fn from_name(name: &str) -> Option<ValueType> {
+        match name {
+            "int" => Some(ValueType::Int),
+            "float" => Some(ValueType::Float),
+            "bool" => Some(ValueType::Bool),
+            "timestamp" => Some(ValueType::Timestamp),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq)]
 pub enum Aggregation<'input> {
-    Count(Vec<&'input str>),
+    Count(Vec<FieldPath<'input>>),
 }
 
 #[derive(PartialEq)]
 pub enum Sort<'input> {
-    Desc(Vec<&'input str>),
-    Asc(Vec<&'input str>),
+    Desc(Vec<FieldPath<'input>>),
+    Asc(Vec<FieldPath<'input>>),
     // Error,
 }
 
+impl<'input> Deref for Literal<'input> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl<'input> AsRef<str> for Literal<'input> {
+    fn as_ref(&self) -> &str {
+        &self.value
+    }
+}
+
+// where it was written doesn't make it a different string
+impl<'input> PartialEq for Literal<'input> {
+    fn eq(&self, other: &Literal<'input>) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<'input> From<&'input str> for Literal<'input> {
+    fn from(value: &'input str) -> Literal<'input> {
+        Literal {
+            value: Cow::Borrowed(value),
+            span: (0, value.len()),
+        }
+    }
+}
+
+impl<'input> Debug for Literal<'input> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{:?}", self.value)
+    }
+}
+
 impl<'input> Debug for SearchTerm<'input> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         use self::SearchTerm::*;
-        match *self {
+        match self {
             Include(term) => write!(fmt, "{:?}", term),
             Exclude(term) => write!(fmt, "! {:?}", term),
             Any() => write!(fmt, "*")
@@ -57,6 +139,11 @@ impl<'input> Debug for Transform<'input> {
             Aggregate(aggregation) => write!(fmt, "| {:?}", aggregation),
             Filter { field, comparison, value} => write!(fmt, "| where {:?} {:?} {:?}", field, comparison, value),
             Parse { field, parser, bindings} => write!(fmt, "| parse {:?} {:?} {:?}", field, parser, bindings),
+            Cast { field, to } => write!(fmt, "| cast {:?} as {:?}", field, to),
+            ParseTime { field, format, target } => write!(fmt, "| parse_time({:?}, {:?}) as {:?}", field, format, target),
+            FormatTime { field, format, zone, target } => write!(fmt, "| format_time({:?}, {:?}, {:?}) as {:?}", field, format, zone, target),
+            TimeDiff { from, to, target } => write!(fmt, "| time_diff({:?}, {:?}) as {:?}", from, to, target),
+            ConvertTz { field, zone, target } => write!(fmt, "| convert_tz({:?}, {:?}) as {:?}", field, zone, target),
         }
     }
 }
@@ -87,6 +174,31 @@ impl<'input> Debug for Comparison {
             Ne => write!(fmt, "!="),
             Eq => write!(fmt, "="),
             Match => write!(fmt, "match"),
+            Lt => write!(fmt, "<"),
+            Le => write!(fmt, "<="),
+            Gt => write!(fmt, ">"),
+            Ge => write!(fmt, ">="),
+        }
+    }
+}
+
+impl<'input> Debug for Binding<'input> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match &self.cast {
+            Some(cast) => write!(fmt, "{:?}:{:?}", self.field, cast),
+            None => write!(fmt, "{:?}", self.field),
+        }
+    }
+}
+
+impl Debug for ValueType {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        use self::ValueType::*;
+        match *self {
+            Int => write!(fmt, "int"),
+            Float => write!(fmt, "float"),
+            Bool => write!(fmt, "bool"),
+            Timestamp => write!(fmt, "timestamp"),
         }
     }
 }
\ No newline at end of file
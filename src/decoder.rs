@@ -0,0 +1,236 @@
+use crate::preset::parse_logfmt;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+
+lazy_static! {
+    // `2020-05-30T09:51:27.5Z stdout F message`, as written by containerd and cri-o
+    static ref CRI_LINE: Regex = Regex::new(r"^(\S+) (stdout|stderr) ([PF])(?: (.*))?$").unwrap();
+}
+
+// what the lines of a source are written in; the container runtimes' formats are turned into the
+// `{"log", "stream", "time"}` records fluentd forwards, with lines they split in pieces joined back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // taken as they are: json objects are events, anything else `_raw`
+    Lines,
+    // every line is `_raw`, even one that reads as json
+    Text,
+    // `key=value` pairs, kept next to the line they came from as `_raw`
+    Logfmt,
+    Cri,
+    // docker's json-file driver, which splits lines over 16k in records without a trailing newline
+    DockerJson,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "lines" | "json" => Some(Format::Lines),
+            "text" => Some(Format::Text),
+            "logfmt" => Some(Format::Logfmt),
+            "cri" => Some(Format::Cri),
+            "docker" => Some(Format::DockerJson),
+            _ => None,
+        }
+    }
+
+    // a record, and whether the next one of its stream carries on its line
+    fn record(self, line: &str) -> Option<(Map<String, Value>, bool)> {
+        match self {
+            Format::Lines | Format::Text | Format::Logfmt => None,
+            Format::Cri => CRI_LINE.captures(line).map(|cap| {
+                let partial = &cap[3] == "P";
+                let mut log = cap.get(4).map_or("", |m| m.as_str()).to_owned();
+                if !partial {
+                    log.push('\n');
+                }
+                let record = json!({"log": log, "stream": &cap[2], "time": &cap[1]});
+                (record.as_object().unwrap().clone(), partial)
+            }),
+            Format::DockerJson => match serde_json::from_str(line) {
+                Ok(Value::Object(record)) => {
+                    let partial = match record.get("log") {
+                        Some(Value::String(log)) => !log.ends_with('\n'),
+                        _ => return None,
+                    };
+                    Some((record, partial))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+fn stream(record: &Map<String, Value>) -> String {
+    record.get("stream").and_then(Value::as_str).unwrap_or("").to_owned()
+}
+
+// the pieces of one line in file order: the first one's fields with all the logs
+fn join(mut pieces: Vec<Map<String, Value>>) -> String {
+    let log: String = pieces
+        .iter()
+        .filter_map(|piece| piece.get("log").and_then(Value::as_str))
+        .collect();
+    let mut record = pieces.remove(0);
+    record.insert("log".to_owned(), Value::String(log));
+    Value::Object(record).to_string()
+}
+
+fn logfmt_record(line: String) -> String {
+    let mut record = parse_logfmt(&line);
+    if record.is_empty() {
+        return line;
+    }
+    record.insert("_raw".to_owned(), Value::String(line));
+    Value::Object(record).to_string()
+}
+
+struct Decoded<'a> {
+    lines: Box<dyn Iterator<Item = String> + 'a>,
+    format: Format,
+    backwards: bool,
+    // per stream, the pieces of the line being put together, in reading order
+    pending: HashMap<String, Vec<Map<String, Value>>>,
+    ready: VecDeque<String>,
+}
+
+impl<'a> Decoded<'a> {
+    fn flush(&mut self, stream: &str) {
+        if let Some(mut pieces) = self.pending.remove(stream) {
+            if self.backwards {
+                pieces.reverse();
+            }
+            self.ready.push_back(join(pieces));
+        }
+    }
+
+    fn read(&mut self, line: String) {
+        let (record, partial) = match self.format.record(&line) {
+            Some(record) => record,
+            None => {
+                self.ready.push_back(line);
+                return;
+            }
+        };
+        let stream = stream(&record);
+        if self.backwards {
+            // the last piece of a line is read first: a complete record closes the line before it
+            if !partial {
+                self.flush(&stream);
+            }
+            self.pending.entry(stream).or_insert_with(Vec::new).push(record);
+        } else {
+            self.pending.entry(stream.clone()).or_insert_with(Vec::new).push(record);
+            if !partial {
+                self.flush(&stream);
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Decoded<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        while self.ready.is_empty() {
+            match self.lines.next() {
+                Some(line) => self.read(line),
+                None => {
+                    let mut streams: Vec<String> = self.pending.keys().cloned().collect();
+                    streams.sort();
+                    for stream in streams {
+                        self.flush(&stream);
+                    }
+                    break;
+                }
+            }
+        }
+        self.ready.pop_front()
+    }
+}
+
+// `lines` are in file order, or last to first when `backwards`; records come out the same way.
+// lines that aren't in `format` pass through untouched
+pub fn decode<'a>(
+    lines: Box<dyn Iterator<Item = String> + 'a>,
+    format: Format,
+    backwards: bool,
+) -> Box<dyn Iterator<Item = String> + 'a> {
+    match format {
+        Format::Lines | Format::Text => return lines,
+        Format::Logfmt => return Box::new(lines.map(logfmt_record)),
+        Format::Cri | Format::DockerJson => {}
+    }
+    Box::new(Decoded {
+        lines,
+        format,
+        backwards,
+        pending: HashMap::new(),
+        ready: VecDeque::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Format};
+    use serde_json::{json, Value};
+
+    fn run(format: Format, lines: &[&str], backwards: bool) -> Vec<Value> {
+        let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        if backwards {
+            lines.reverse();
+        }
+        let mut records: Vec<Value> = decode(Box::new(lines.into_iter()), format, backwards)
+            .map(|line| serde_json::from_str(&line).unwrap_or(Value::String(line)))
+            .collect();
+        if backwards {
+            records.reverse();
+        }
+        records
+    }
+
+    #[test]
+    fn decodes_cri_lines_joining_partials() {
+        let lines = [
+            "2020-05-30T09:51:27.1Z stdout P GET /icons/",
+            "2020-05-30T09:51:27.2Z stderr F warning",
+            "2020-05-30T09:51:27.3Z stdout F seventh_son.png 200",
+            "not cri",
+        ];
+        for backwards in &[false, true] {
+            let records = run(Format::Cri, &lines, *backwards);
+            assert!(records.contains(&json!({"log": "GET /icons/seventh_son.png 200\n", "stream": "stdout", "time": "2020-05-30T09:51:27.1Z"})));
+            assert!(records.contains(&json!({"log": "warning\n", "stream": "stderr", "time": "2020-05-30T09:51:27.2Z"})));
+            assert!(records.contains(&json!("not cri")));
+            assert_eq!(3, records.len());
+        }
+    }
+
+    #[test]
+    fn decodes_logfmt_pairs() {
+        let line = r#"time="2020-05-30T00:01:19Z" level=info msg="node get capabilities called""#;
+        assert_eq!(
+            vec![
+                json!({"time": "2020-05-30T00:01:19Z", "level": "info", "msg": "node get capabilities called", "_raw": line}),
+                json!("no pairs here"),
+            ],
+            run(Format::Logfmt, &[line, "no pairs here"], false)
+        );
+    }
+
+    #[test]
+    fn decodes_docker_json_joining_split_lines() {
+        let lines = [
+            r#"{"log":"aaaa","stream":"stdout","time":"2020-05-30T09:51:27.1Z"}"#,
+            r#"{"log":"bbbb\n","stream":"stdout","time":"2020-05-30T09:51:27.2Z"}"#,
+            r#"{"log":"cccc\n","stream":"stdout","time":"2020-05-30T09:51:27.3Z"}"#,
+        ];
+        let expected = vec![
+            json!({"log": "aaaabbbb\n", "stream": "stdout", "time": "2020-05-30T09:51:27.1Z"}),
+            json!({"log": "cccc\n", "stream": "stdout", "time": "2020-05-30T09:51:27.3Z"}),
+        ];
+        assert_eq!(expected, run(Format::DockerJson, &lines, false));
+        assert_eq!(expected, run(Format::DockerJson, &lines, true));
+    }
+}
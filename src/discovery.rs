@@ -0,0 +1,206 @@
+use crate::source::is_log_file;
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// a shell-like glob: `*` and `?` stay within a path segment, `**` crosses them.
+// one with a `/` is matched against the whole path (absolute when it starts with `/`,
+// else under the logs dir), one without just against the file name
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: Regex,
+    whole_path: bool,
+    absolute: bool,
+}
+
+impl Glob {
+    pub fn parse(glob: &str) -> Result<Glob, String> {
+        let mut pattern = String::from("^");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        pattern.push_str("(?:.*/)?");
+                    } else {
+                        pattern.push_str(".*");
+                    }
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                '[' => {
+                    pattern.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        pattern.push('^');
+                    }
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some('\\') => pattern.push_str("\\\\"),
+                            Some(c) => pattern.push(c),
+                            None => return Err(format!("unclosed `[` in `{}`", glob)),
+                        }
+                    }
+                    pattern.push(']');
+                }
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Ok(Glob {
+            pattern: Regex::new(&pattern).map_err(|e| e.to_string())?,
+            whole_path: glob.contains('/'),
+            absolute: glob.starts_with('/'),
+        })
+    }
+
+    fn matches(&self, relative: &Path, absolute: &Path) -> bool {
+        let path = if !self.whole_path {
+            relative.file_name().map(Path::new).unwrap_or(relative)
+        } else if self.absolute {
+            absolute
+        } else {
+            relative
+        };
+        self.pattern.is_match(&path.to_string_lossy())
+    }
+}
+
+// which files under the logs dir are searched: without includes every log file, as in
+// `source::is_log_file`, with them whatever they match; excludes win over both
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    pub include: Vec<Glob>,
+    pub exclude: Vec<Glob>,
+    pub follow_symlinks: bool,
+}
+
+impl Default for Discovery {
+    fn default() -> Discovery {
+        Discovery {
+            include: vec![],
+            exclude: vec![],
+            follow_symlinks: true,
+        }
+    }
+}
+
+impl Discovery {
+    fn wants(&self, relative: &Path, absolute: &Path) -> bool {
+        let included = if self.include.is_empty() {
+            is_log_file(relative)
+        } else {
+            self.include.iter().any(|glob| glob.matches(relative, absolute))
+        };
+        included && !self.exclude.iter().any(|glob| glob.matches(relative, absolute))
+    }
+
+    // the wanted files under `root` as (path under root, path), subdirectories in name order.
+    // a file reached through several symlinks is listed once, under the first of its paths,
+    // and a directory is only entered once, so symlink loops end. a root that isn't there has
+    // no files
+    pub fn discover(&self, root: &Path) -> Vec<(PathBuf, PathBuf)> {
+        let root = match fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(_) => return vec![],
+        };
+        let mut found = vec![];
+        let mut seen_dirs = HashSet::new();
+        let mut seen_files = HashSet::new();
+        seen_dirs.insert(root.clone());
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let mut entries: Vec<_> = match fs::read_dir(root.join(&dir)) {
+                Ok(entries) => entries.filter_map(Result::ok).collect(),
+                Err(_) => continue,
+            };
+            entries.sort_by_key(|entry| entry.file_name());
+            let mut subdirs = vec![];
+            for entry in entries {
+                let relative = dir.join(entry.file_name());
+                let path = root.join(&relative);
+                let is_symlink = entry.file_type().map_or(false, |t| t.is_symlink());
+                if is_symlink && !self.follow_symlinks {
+                    continue;
+                }
+                let metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let canonical = match fs::canonicalize(&path) {
+                    Ok(canonical) => canonical,
+                    Err(_) => continue,
+                };
+                if metadata.is_dir() {
+                    if seen_dirs.insert(canonical) {
+                        subdirs.push(relative);
+                    }
+                } else if metadata.is_file() && self.wants(&relative, &path) && seen_files.insert(canonical) {
+                    found.push((relative, path));
+                }
+            }
+            pending.extend(subdirs.into_iter().rev());
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Discovery, Glob};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn globs_stay_within_segments() {
+        let glob = |g: &str, path: &str| Glob::parse(g).unwrap().matches(Path::new(path), &Path::new("/logs").join(path));
+        assert!(glob("*kube-system*", "containers/coredns_kube-system_coredns.log"));
+        assert!(glob("containers/*.log", "containers/a.log"));
+        assert!(!glob("containers/*.log", "containers/pods/a.log"));
+        assert!(glob("**/0.log", "pods/ns_pod_uid/app/0.log"));
+        assert!(glob("**/0.log", "0.log"));
+        assert!(glob("/logs/pods/*/app/?.log", "pods/ns_pod_uid/app/0.log"));
+        assert!(glob("app.log.[!0-9]*", "app.log.gz"));
+        assert!(!glob("app.log.[!0-9]*", "app.log.1"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_without_looping() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join(format!("still-discovery-{}", std::process::id()));
+        let pod = root.join("pods/kitchen_oven-1_uid/oven");
+        fs::create_dir_all(&pod).unwrap();
+        fs::create_dir_all(root.join("containers")).unwrap();
+        fs::write(pod.join("0.log"), "{}\n").unwrap();
+        fs::write(root.join("containers/kube-proxy_kube-system_proxy-abc.log"), "{}\n").unwrap();
+        symlink(pod.join("0.log"), root.join("containers/oven-1_kitchen_oven-abc.log")).unwrap();
+        symlink(&root, root.join("pods/loop")).unwrap();
+
+        let discovery = Discovery {
+            exclude: vec![Glob::parse("*kube-system*").unwrap()],
+            ..Discovery::default()
+        };
+        let found: Vec<PathBuf> = discovery.discover(&root).into_iter().map(|(relative, _)| relative).collect();
+        assert_eq!(vec![PathBuf::from("containers/oven-1_kitchen_oven-abc.log")], found);
+
+        let discovery = Discovery {
+            follow_symlinks: false,
+            ..Discovery::default()
+        };
+        let found: Vec<PathBuf> = discovery.discover(&root).into_iter().map(|(relative, _)| relative).collect();
+        assert_eq!(
+            vec![
+                PathBuf::from("containers/kube-proxy_kube-system_proxy-abc.log"),
+                PathBuf::from("pods/kitchen_oven-1_uid/oven/0.log"),
+            ],
+            found
+        );
+        fs::remove_dir_all(&root).unwrap();
+        assert!(discovery.discover(&root).is_empty());
+    }
+}
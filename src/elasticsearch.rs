@@ -0,0 +1,154 @@
+use chrono::Utc;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+lazy_static! {
+    // what still takes as an index name: it becomes a dir under `ingest/indices`
+    static ref INDEX_NAME: Regex = Regex::new(r"^[a-z0-9][a-z0-9._-]{0,254}$").unwrap();
+}
+
+pub fn valid_index(name: &str) -> bool {
+    INDEX_NAME.is_match(name)
+}
+
+// what came of one action of a bulk request
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub action: String,
+    pub index: String,
+    pub id: String,
+    // the document to store, or the status and error to answer with
+    pub outcome: Result<Value, (u16, &'static str, String)>,
+}
+
+impl Item {
+    // the item as it appears in the bulk response
+    pub fn to_json(&self) -> Value {
+        let mut item = json!({ "_index": self.index, "_id": self.id });
+        match &self.outcome {
+            Ok(_) => {
+                item["status"] = json!(201);
+                item["result"] = json!("created");
+            }
+            Err((status, kind, reason)) => {
+                item["status"] = json!(status);
+                item["error"] = json!({ "type": kind, "reason": reason });
+            }
+        }
+        json!({ self.action.clone(): item })
+    }
+}
+
+// the ndjson of a `_bulk` request: action lines, each but deletes followed by a document. only
+// `index` and `create` are taken; the others are answered as failed items. `default_index` is the
+// one in the path, for actions without their own
+pub fn decode_bulk(text: &str, default_index: Option<&str>) -> Result<Vec<Item>, String> {
+    let stamp = Utc::now().timestamp_nanos();
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let mut items = vec![];
+    while let Some(line) = lines.next() {
+        let (action, meta) = match serde_json::from_str::<Map<String, Value>>(line) {
+            Ok(ref object) if object.len() == 1 => {
+                let (action, meta) = object.iter().next().unwrap();
+                (action.clone(), meta.clone())
+            }
+            _ => return Err(format!("malformed action/metadata line [{}]", items.len() + 1)),
+        };
+        let index = meta["_index"].as_str().or(default_index).unwrap_or("").to_owned();
+        let id = meta["_id"].as_str().map_or_else(|| format!("{:x}-{}", stamp, items.len()), str::to_owned);
+        let outcome = match action.as_str() {
+            "index" | "create" => {
+                let document = lines.next().ok_or("the bulk request ends without a document")?;
+                if !valid_index(&index) {
+                    Err((400, "invalid_index_name_exception", format!("invalid index name [{}]", index)))
+                } else {
+                    match serde_json::from_str(document) {
+                        Ok(Value::Object(mut document)) => {
+                            document.entry("_index").or_insert_with(|| json!(index));
+                            document.entry("_id").or_insert_with(|| json!(id));
+                            Ok(Value::Object(document))
+                        }
+                        Ok(_) => Err((400, "mapper_parsing_exception", "the document should be a json object".to_owned())),
+                        Err(e) => Err((400, "mapper_parsing_exception", e.to_string())),
+                    }
+                }
+            }
+            "update" => {
+                lines.next().ok_or("the bulk request ends without a document")?;
+                Err((400, "action_request_validation_exception", "stored events can't be updated".to_owned()))
+            }
+            "delete" => Err((400, "action_request_validation_exception", "stored events can't be deleted".to_owned())),
+            other => return Err(format!("unknown bulk action [{}]", other)),
+        };
+        items.push(Item { action, index, id, outcome });
+    }
+    Ok(items)
+}
+
+// the elasticsearch version still passes for, one whose bulk api the clients all speak
+const VERSION: &str = "7.10.2";
+
+// the body of `GET /`, where clients look for the version before they start
+pub fn root() -> Value {
+    json!({
+        "name": "still",
+        "cluster_name": "still",
+        "version": {
+            "number": VERSION,
+            "build_flavor": "default",
+            "lucene_version": "8.7.0",
+            "minimum_wire_compatibility_version": "6.8.0",
+            "minimum_index_compatibility_version": "6.0.0-beta1",
+        },
+        "tagline": "You Know, for Search",
+    })
+}
+
+// the body of a bulk response
+pub fn response(items: &[Item], took: i64) -> Value {
+    json!({
+        "took": took,
+        "errors": items.iter().any(|item| item.outcome.is_err()),
+        "items": items.iter().map(Item::to_json).collect::<Vec<Value>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_bulk, response};
+    use serde_json::json;
+
+    #[test]
+    fn takes_index_and_create_actions() {
+        let body = r#"{"index": {"_index": "nginx", "_id": "1"}}
+{"log": "GET /"}
+{"create": {}}
+{"log": "POST /"}
+{"delete": {"_index": "nginx", "_id": "1"}}
+{"update": {"_id": "1"}}
+{"doc": {"log": "PUT /"}}
+{"index": {"_index": "../etc"}}
+{"log": "nope"}
+"#;
+        let items = decode_bulk(body, Some("default")).unwrap();
+        assert_eq!(Ok(json!({"log": "GET /", "_index": "nginx", "_id": "1"})), items[0].outcome);
+        assert_eq!("default", items[1].index);
+        assert!(items[1].outcome.is_ok());
+        let statuses: Vec<_> = items.iter().map(|item| item.outcome.as_ref().err().map(|e| e.0)).collect();
+        assert_eq!(vec![None, None, Some(400), Some(400), Some(400)], statuses);
+
+        let body = response(&items[..1], 3);
+        assert_eq!(
+            json!({"took": 3, "errors": false, "items": [{"index": {"_index": "nginx", "_id": "1", "status": 201, "result": "created"}}]}),
+            body
+        );
+        assert_eq!(json!(true), response(&items, 3)["errors"]);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(decode_bulk("{\"index\": {}}\n", Some("a")).is_err());
+        assert!(decode_bulk("not json\n{}\n", Some("a")).is_err());
+        assert!(decode_bulk("{\"upsert\": {}}\n{}\n", Some("a")).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::literal::{unescape, LiteralError};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt::{Debug, Display, Error, Formatter};
+use std::hash::{Hash, Hasher};
+
+// where a field lives in an event: `kubernetes.labels."pod-template-hash"`, `containers[0].name`
+#[derive(Clone)]
+pub struct FieldPath<'input> {
+    pub segments: Vec<Segment<'input>>,
+    // where it was written in the query, for error reporting
+    pub span: (usize, usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Segment<'input> {
+    Key(Cow<'input, str>),
+    Index(usize),
+}
+
+fn is_bare(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+impl<'input> FieldPath<'input> {
+    // `offset` is where `source` starts in the query, so errors point at the right spot
+    pub fn parse(source: &'input str, offset: usize) -> Result<FieldPath<'input>, LiteralError> {
+        let error = |start: usize, end: usize, message: &str| LiteralError {
+            offset: offset + start,
+            end: offset + end,
+            message: format!("{} in field {:?}", message, source),
+        };
+        let mut segments = vec![];
+        let mut position = 0;
+        let mut expect_segment = true;
+        while position < source.len() {
+            let rest = &source[position..];
+            let next = rest.chars().next().unwrap();
+            if expect_segment {
+                if next == '"' {
+                    let close = closing_quote(rest)
+                        .ok_or_else(|| error(position, source.len(), "unterminated quoted segment"))?;
+                    segments.push(Segment::Key(unescape(&rest[1..close], offset + position + 1)?));
+                    position += close + 1;
+                } else {
+                    let len = rest.find(|c| !is_bare(c)).unwrap_or_else(|| rest.len());
+                    if len == 0 {
+                        return Err(error(position, position + next.len_utf8(), "expected a field name"));
+                    }
+                    segments.push(Segment::Key(Cow::Borrowed(&rest[..len])));
+                    position += len;
+                }
+                expect_segment = false;
+            } else if next == '.' {
+                position += 1;
+                expect_segment = true;
+            } else if next == '[' {
+                let close = rest
+                    .find(']')
+                    .ok_or_else(|| error(position, source.len(), "unterminated index"))?;
+                let index = rest[1..close]
+                    .parse()
+                    .map_err(|_| error(position, position + close + 1, "invalid index"))?;
+                segments.push(Segment::Index(index));
+                position += close + 1;
+            } else {
+                return Err(error(position, position + next.len_utf8(), "expected '.' or '['"));
+            }
+        }
+        if expect_segment {
+            return Err(error(source.len(), source.len(), "expected a field name"));
+        }
+        Ok(FieldPath {
+            segments,
+            span: (offset, offset + source.len()),
+        })
+    }
+
+    // None when any step is missing, or when stepping into something that isn't an object/array.
+    // a present `null` is Some(Null): it's there, it just has no value
+    pub fn get<'v>(&self, event: &'v Value) -> Option<&'v Value> {
+        self.segments.iter().try_fold(event, |current, segment| match segment {
+            Segment::Key(key) => current.as_object()?.get(key.as_ref()),
+            Segment::Index(index) => current.as_array()?.get(*index),
+        })
+    }
+
+    // creates whatever is missing on the way; a scalar in the way is replaced by an object (or an
+    // array for indexes), and arrays are padded with nulls up to the index being written
+    pub fn set(&self, event: &mut Value, value: Value) {
+        let target = self.segments.iter().fold(event, |current, segment| match segment {
+            Segment::Key(key) => {
+                if !current.is_object() {
+                    *current = Value::Object(Map::new());
+                }
+                current
+                    .as_object_mut()
+                    .unwrap()
+                    .entry(key.as_ref())
+                    .or_insert(Value::Null)
+            }
+            Segment::Index(index) => {
+                if !current.is_array() {
+                    *current = Value::Array(vec![]);
+                }
+                let array = current.as_array_mut().unwrap();
+                if array.len() <= *index {
+                    array.resize(*index + 1, Value::Null);
+                }
+                &mut array[*index]
+            }
+        });
+        *target = value;
+    }
+}
+
+fn closing_quote(quoted: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in quoted.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+impl<'input> Display for FieldPath<'input> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Key(key) => {
+                    if i > 0 {
+                        write!(fmt, ".")?;
+                    }
+                    if !key.is_empty() && key.chars().all(is_bare) {
+                        write!(fmt, "{}", key)?;
+                    } else {
+                        write!(fmt, "\"{}\"", key.escape_default())?;
+                    }
+                }
+                Segment::Index(index) => write!(fmt, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// `a.b` and `a."b"` are the same field
+impl<'input> PartialEq for FieldPath<'input> {
+    fn eq(&self, other: &FieldPath<'input>) -> bool {
+        self.segments == other.segments
+    }
+}
+
+impl<'input> Eq for FieldPath<'input> {}
+
+impl<'input> Hash for FieldPath<'input> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.segments.hash(state);
+    }
+}
+
+impl<'input> Debug for FieldPath<'input> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldPath, Segment};
+    use serde_json::json;
+    use std::borrow::Cow;
+
+    fn key(key: &str) -> Segment {
+        Segment::Key(Cow::Borrowed(key))
+    }
+
+    #[test]
+    fn splits_dotted_paths() {
+        let path = FieldPath::parse("kubernetes.namespace_name", 0).unwrap();
+        assert_eq!(vec![key("kubernetes"), key("namespace_name")], path.segments);
+    }
+
+    #[test]
+    fn quoted_segments_keep_dots_and_slashes() {
+        let path = FieldPath::parse(r#"kubernetes.annotations."clusterlint.digitalocean.com/disabled-checks""#, 0).unwrap();
+        assert_eq!(
+            vec![key("kubernetes"), key("annotations"), key("clusterlint.digitalocean.com/disabled-checks")],
+            path.segments
+        );
+        assert_eq!(vec![key("a~b")], FieldPath::parse(r#""a~b""#, 0).unwrap().segments);
+    }
+
+    #[test]
+    fn indexes_arrays() {
+        let path = FieldPath::parse("containers[0].name", 0).unwrap();
+        assert_eq!(vec![key("containers"), Segment::Index(0), key("name")], path.segments);
+        assert_eq!("containers[0].name", path.to_string());
+    }
+
+    #[test]
+    fn accepts_unicode_names() {
+        let path = FieldPath::parse("données.clé", 0).unwrap();
+        assert_eq!(vec![key("données"), key("clé")], path.segments);
+    }
+
+    #[test]
+    fn gets_nested_and_indexed_values() {
+        let event = json!({"kubernetes": {"namespace_name": "netronner"}, "containers": [{"name": "ui"}], "log": "x"});
+        assert_eq!(Some(&json!("netronner")), FieldPath::parse("kubernetes.namespace_name", 0).unwrap().get(&event));
+        assert_eq!(Some(&json!("ui")), FieldPath::parse("containers[0].name", 0).unwrap().get(&event));
+        assert_eq!(None, FieldPath::parse("containers[1].name", 0).unwrap().get(&event));
+        assert_eq!(None, FieldPath::parse("log.nested", 0).unwrap().get(&event));
+    }
+
+    #[test]
+    fn sets_creating_what_is_missing() {
+        let mut event = json!({"log": "x", "kubernetes": {"host": "worker"}});
+        FieldPath::parse("kubernetes.namespace_name", 0).unwrap().set(&mut event, json!("netronner"));
+        FieldPath::parse("log.verb", 0).unwrap().set(&mut event, json!("GET"));
+        FieldPath::parse("tags[1]", 0).unwrap().set(&mut event, json!("b"));
+        assert_eq!(
+            json!({
+                "log": {"verb": "GET"},
+                "kubernetes": {"host": "worker", "namespace_name": "netronner"},
+                "tags": [null, "b"]
+            }),
+            event
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert_eq!(14, FieldPath::parse("a..b", 12).unwrap_err().offset);
+        assert_eq!(11, FieldPath::parse("a[x]", 10).unwrap_err().offset);
+        assert_eq!(4, FieldPath::parse("a.", 2).unwrap_err().offset);
+        assert_eq!(1, FieldPath::parse(r#"a"b""#, 0).unwrap_err().offset);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::ingest::{serve_clients, Store};
+use crate::timestamp;
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::MultiGzDecoder;
+use rmpv::Value as Pack;
+use serde_json::{json, Map, Value};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+// no message, nor the entries of a compressed one once gunzipped, is bigger than this
+const MAX_MESSAGE: u64 = 16 * 1024 * 1024;
+// nor nests maps and arrays deeper
+const MAX_DEPTH: usize = 64;
+
+fn read_bytes<R: Read>(reader: &mut R, raw: &mut Vec<u8>, length: u64, limit: u64) -> Result<(), String> {
+    if raw.len() as u64 + length > limit {
+        return Err(format!("a message is over {} bytes", limit));
+    }
+    let read = reader.take(length).read_to_end(raw).map_err(|e| e.to_string())?;
+    if (read as u64) < length {
+        return Err("the message ends early".to_owned());
+    }
+    Ok(())
+}
+
+fn read_length<R: Read>(reader: &mut R, raw: &mut Vec<u8>, size: usize, limit: u64) -> Result<u64, String> {
+    read_bytes(reader, raw, size as u64, limit)?;
+    Ok(raw[raw.len() - size..].iter().fold(0, |length, byte| length << 8 | u64::from(*byte)))
+}
+
+// the bytes of the next msgpack value, None at the end of the input. they are only walked
+// through, a nesting level at a time, so a huge or deep value fails before anything decodes it
+fn read_message<R: Read>(reader: &mut R, limit: u64) -> Result<Option<Vec<u8>>, String> {
+    let mut raw = vec![];
+    // how many values are still to come at each level
+    let mut pending = vec![1u64];
+    while let Some(left) = pending.last_mut() {
+        if *left == 0 {
+            pending.pop();
+            continue;
+        }
+        *left -= 1;
+        let mut marker = [0];
+        if reader.read(&mut marker).map_err(|e| e.to_string())? == 0 {
+            return if raw.is_empty() { Ok(None) } else { Err("the message ends early".to_owned()) };
+        }
+        raw.push(marker[0]);
+        let (data, values) = match marker[0] {
+            0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0),
+            0x80..=0x8f => (0, 2 * u64::from(marker[0] & 0x0f)),
+            0x90..=0x9f => (0, u64::from(marker[0] & 0x0f)),
+            0xa0..=0xbf => (u64::from(marker[0] & 0x1f), 0),
+            0xc4 | 0xd9 => (read_length(reader, &mut raw, 1, limit)?, 0),
+            0xc5 | 0xda => (read_length(reader, &mut raw, 2, limit)?, 0),
+            0xc6 | 0xdb => (read_length(reader, &mut raw, 4, limit)?, 0),
+            0xc7 => (read_length(reader, &mut raw, 1, limit)? + 1, 0),
+            0xc8 => (read_length(reader, &mut raw, 2, limit)? + 1, 0),
+            0xc9 => (read_length(reader, &mut raw, 4, limit)? + 1, 0),
+            0xca | 0xce | 0xd2 => (4, 0),
+            0xcb | 0xcf | 0xd3 => (8, 0),
+            0xcc | 0xd0 => (1, 0),
+            0xcd | 0xd1 => (2, 0),
+            0xd4..=0xd8 => (1 + (1 << (marker[0] - 0xd4)), 0),
+            0xdc => (0, read_length(reader, &mut raw, 2, limit)?),
+            0xdd => (0, read_length(reader, &mut raw, 4, limit)?),
+            0xde => (0, 2 * read_length(reader, &mut raw, 2, limit)?),
+            0xdf => (0, 2 * read_length(reader, &mut raw, 4, limit)?),
+            0xc1 => return Err("not msgpack".to_owned()),
+        };
+        read_bytes(reader, &mut raw, data, limit)?;
+        if values > 0 {
+            if pending.len() == MAX_DEPTH {
+                return Err(format!("a message nests deeper than {} levels", MAX_DEPTH));
+            }
+            pending.push(values);
+        }
+    }
+    Ok(Some(raw))
+}
+
+fn read_value<R: Read>(reader: &mut R, limit: u64) -> Result<Option<Pack>, String> {
+    match read_message(reader, limit)? {
+        Some(raw) => rmpv::decode::read_value(&mut raw.as_slice()).map(Some).map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+// msgpack as json: binary as (lossy) text, extension types as null
+fn to_json(pack: &Pack) -> Value {
+    match pack {
+        Pack::Nil | Pack::Ext(_, _) => Value::Null,
+        Pack::Boolean(b) => json!(b),
+        Pack::Integer(i) => i.as_i64().map(|i| json!(i)).or_else(|| i.as_u64().map(|u| json!(u))).unwrap_or(Value::Null),
+        Pack::F32(f) => json!(f),
+        Pack::F64(f) => json!(f),
+        Pack::String(s) => json!(String::from_utf8_lossy(s.as_bytes())),
+        Pack::Binary(b) => json!(String::from_utf8_lossy(b)),
+        Pack::Array(items) => Value::Array(items.iter().map(to_json).collect()),
+        Pack::Map(pairs) => {
+            let object: Map<String, Value> = pairs.iter().map(|(k, v)| (key(k), to_json(v))).collect();
+            Value::Object(object)
+        }
+    }
+}
+
+fn key(pack: &Pack) -> String {
+    match pack {
+        Pack::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        Pack::Binary(b) => String::from_utf8_lossy(b).into_owned(),
+        other => to_json(other).to_string(),
+    }
+}
+
+fn text(pack: &Pack) -> Option<String> {
+    match pack {
+        Pack::String(s) => Some(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Pack::Binary(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        _ => None,
+    }
+}
+
+// seconds, or fluentd's EventTime: extension 0 holding big endian seconds and nanoseconds
+fn event_time(pack: &Pack) -> Option<DateTime<Utc>> {
+    match pack {
+        Pack::Integer(i) => i.as_i64().and_then(|s| Utc.timestamp_opt(s, 0).single()),
+        Pack::F64(f) => timestamp::from_epoch_seconds(*f),
+        Pack::Ext(0, data) if data.len() == 8 => {
+            let seconds = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+            let nanos = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            Utc.timestamp_opt(i64::from(seconds), nanos).single()
+        }
+        _ => None,
+    }
+}
+
+// a record as still stores it: as sent, plus the tag and, when it has none of its own, the time
+fn event(tag: &str, time: &Pack, record: &Pack) -> Result<Value, String> {
+    let mut event = match to_json(record) {
+        Value::Object(event) => event,
+        _ => return Err("a record should be a map".to_owned()),
+    };
+    event.entry("_tag").or_insert_with(|| json!(tag));
+    if !event.contains_key("time") {
+        let time = event_time(time).ok_or("unreadable event time")?;
+        event.insert("time".to_owned(), json!(timestamp::canonical(&time)));
+    }
+    Ok(Value::Object(event))
+}
+
+// the `[time, record]` entries of a PackedForward, one after the other, maybe gzipped
+fn unpack_entries(bytes: &[u8], compressed: bool) -> Result<Vec<Pack>, String> {
+    let mut raw = vec![];
+    let mut reader: &[u8] = if compressed {
+        MultiGzDecoder::new(bytes)
+            .take(MAX_MESSAGE + 1)
+            .read_to_end(&mut raw)
+            .map_err(|e| e.to_string())?;
+        if raw.len() as u64 > MAX_MESSAGE {
+            return Err(format!("the entries are over {} bytes gunzipped", MAX_MESSAGE));
+        }
+        raw.as_slice()
+    } else {
+        bytes
+    };
+    let mut entries = vec![];
+    while let Some(entry) = read_value(&mut reader, MAX_MESSAGE)? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// one message of the protocol in any of its modes: Message `[tag, time, record, option]`,
+// Forward `[tag, [[time, record]...], option]` and (Compressed)PackedForward `[tag, bytes, option]`.
+// returns the events and the chunk to acknowledge, if the client asked for that
+pub fn decode(message: &Pack) -> Result<(Vec<Value>, Option<String>), String> {
+    let parts = match message {
+        Pack::Array(parts) if parts.len() >= 2 => parts,
+        _ => return Err("a message should be an array starting with the tag".to_owned()),
+    };
+    let tag = text(&parts[0]).ok_or("the tag should be a string")?;
+    let compressed = || option(parts.get(2), "compressed").and_then(Pack::as_str) == Some("gzip");
+    let (entries, option_at) = match &parts[1] {
+        Pack::Array(entries) => (entries.clone(), 2),
+        Pack::String(s) => (unpack_entries(s.as_bytes(), compressed())?, 2),
+        Pack::Binary(b) => (unpack_entries(b, compressed())?, 2),
+        time => {
+            let record = parts.get(2).ok_or("a message without a record")?;
+            (vec![Pack::Array(vec![time.clone(), record.clone()])], 3)
+        }
+    };
+    let events = entries
+        .iter()
+        .map(|entry| match entry {
+            Pack::Array(pair) if pair.len() == 2 => event(&tag, &pair[0], &pair[1]),
+            _ => Err("an entry should be [time, record]".to_owned()),
+        })
+        .collect::<Result<Vec<Value>, String>>()?;
+    Ok((events, option(parts.get(option_at), "chunk").and_then(text)))
+}
+
+fn option<'p>(options: Option<&'p Pack>, name: &str) -> Option<&'p Pack> {
+    options
+        .and_then(Pack::as_map)
+        .and_then(|options| options.iter().find(|(k, _)| k.as_str() == Some(name)))
+        .map(|(_, v)| v)
+}
+
+// a client's messages, until it hangs up or sends something that isn't the protocol.
+// a chunk is only acknowledged once its events are on disk. when they can't be stored, a full
+// buffer included, the connection is dropped unacknowledged and clients send the chunk again
+fn serve(stream: TcpStream, store: &Store) -> Result<(), String> {
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let message = match read_value(&mut reader, MAX_MESSAGE)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let (events, chunk) = decode(&message)?;
+        store.append(&events, vec![]).map_err(|e| e.to_string())?;
+        if let Some(chunk) = chunk {
+            let ack = Pack::Map(vec![(Pack::from("ack"), Pack::from(chunk))]);
+            rmpv::encode::write_value(&mut writer, &ack).map_err(|e| e.to_string())?;
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+    }
+}
+
+// accepts forward clients on `address` in the background, a thread each
+pub fn listen(address: &str, store: Arc<Store>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let bound = listener.local_addr()?;
+    thread::spawn(move || serve_clients(listener, store, "forward", serve));
+    Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, listen, read_value};
+    use crate::ingest::Store;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use rmpv::Value as Pack;
+    use serde_json::json;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    fn record(log: &str) -> Pack {
+        Pack::Map(vec![(Pack::from("log"), Pack::from(log))])
+    }
+
+    fn event_time() -> Pack {
+        // 2020-05-30T09:51:27.5Z
+        let mut data = 1_590_832_287u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&500_000_000u32.to_be_bytes());
+        Pack::Ext(0, data)
+    }
+
+    #[test]
+    fn decodes_every_mode() {
+        let expected = json!({"log": "hi", "_tag": "kube.var.log", "time": "2020-05-30T09:51:27.500000000Z"});
+        let message = Pack::Array(vec![Pack::from("kube.var.log"), event_time(), record("hi")]);
+        assert_eq!((vec![expected.clone()], None), decode(&message).unwrap());
+
+        let entry = Pack::Array(vec![event_time(), record("hi")]);
+        let option = Pack::Map(vec![(Pack::from("chunk"), Pack::from("c1"))]);
+        let forward = Pack::Array(vec![Pack::from("kube.var.log"), Pack::Array(vec![entry.clone(), entry.clone()]), option.clone()]);
+        assert_eq!((vec![expected.clone(), expected], Some("c1".to_owned())), decode(&forward).unwrap());
+
+        let mut packed = vec![];
+        rmpv::encode::write_value(&mut packed, &entry).unwrap();
+        rmpv::encode::write_value(&mut packed, &entry).unwrap();
+        let packed_forward = Pack::Array(vec![Pack::from("kube.var.log"), Pack::Binary(packed.clone()), option]);
+        assert_eq!(2, decode(&packed_forward).unwrap().0.len());
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&packed).unwrap();
+        let option = Pack::Map(vec![(Pack::from("compressed"), Pack::from("gzip"))]);
+        let compressed = Pack::Array(vec![Pack::from("kube.var.log"), Pack::Binary(gzip.finish().unwrap()), option]);
+        assert_eq!(2, decode(&compressed).unwrap().0.len());
+    }
+
+    #[test]
+    fn bounds_size_and_depth() {
+        let mut message = vec![];
+        rmpv::encode::write_value(&mut message, &Pack::Array(vec![Pack::from("tag"), Pack::Binary(vec![0; 100])])).unwrap();
+        assert!(read_value(&mut message.as_slice(), 100).is_err());
+        assert!(read_value(&mut message.as_slice(), 200).unwrap().is_some());
+        assert!(read_value(&mut &message[..50], 200).is_err());
+        assert_eq!(None, read_value(&mut &b""[..], 200).unwrap());
+
+        let deep = vec![0x91; 100_000];
+        assert!(read_value(&mut deep.as_slice(), 1_000_000).unwrap_err().contains("deeper"));
+
+        // a gzip bomb stops at the limit
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        for _ in 0..320 {
+            gzip.write_all(&[0xc0; 64 * 1024]).unwrap();
+        }
+        let option = Pack::Map(vec![(Pack::from("compressed"), Pack::from("gzip"))]);
+        let bomb = Pack::Array(vec![Pack::from("tag"), Pack::Binary(gzip.finish().unwrap()), option]);
+        assert!(decode(&bomb).is_err());
+    }
+
+    #[test]
+    fn acknowledges_chunks_once_stored() {
+        let dir = std::env::temp_dir().join(format!("still-forward-{}", std::process::id()));
+        let address = listen("127.0.0.1:0", Arc::new(Store::new(dir.clone()))).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        let entry = Pack::Array(vec![event_time(), record("hi")]);
+        let option = Pack::Map(vec![(Pack::from("chunk"), Pack::from("c1"))]);
+        let forward = Pack::Array(vec![Pack::from("kube.var.log"), Pack::Array(vec![entry]), option]);
+        rmpv::encode::write_value(&mut client, &forward).unwrap();
+        client.flush().unwrap();
+        let ack = rmpv::decode::read_value(&mut client).unwrap();
+        assert_eq!(Pack::Map(vec![(Pack::from("ack"), Pack::from("c1"))]), ack);
+        assert_eq!(1, fs::read_to_string(dir.join("2020-05-30.log")).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+use crate::ingest::{receive_datagrams, serve_clients, Store, MAX_BATCH};
+use crate::syslog::SEVERITIES;
+use crate::timestamp;
+use chrono::Utc;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const MAX_CHUNKS: usize = 128;
+// how long the chunks of a message wait for the rest of it, as graylog does
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+// how much the chunks of unfinished messages can take altogether; past it the oldest go
+const MAX_PENDING: usize = 32 * 1024 * 1024;
+// the biggest message taken, over tcp or once inflated
+const MAX_MESSAGE: usize = 8 * 1024 * 1024;
+
+// a gelf message as an event: short_message as `message`, the syslog level also by name and the
+// additional fields without their `_`, next to the standard ones they can't override
+pub fn event(message: &[u8]) -> Result<Value, String> {
+    let gelf: Map<String, Value> = serde_json::from_slice(message).map_err(|e| format!("not a gelf message: {}", e))?;
+    let mut event = Map::new();
+    let mut additional = Map::new();
+    let mut time = None;
+    for (key, value) in gelf {
+        match key.as_str() {
+            "short_message" => {
+                event.insert("message".to_owned(), value);
+            }
+            "timestamp" => time = value.as_f64().and_then(timestamp::from_epoch_seconds),
+            "level" => {
+                if let Some(severity) = value.as_u64().and_then(|level| SEVERITIES.get(level as usize)) {
+                    event.insert("severity".to_owned(), json!(severity));
+                }
+                event.insert(key, value);
+            }
+            "_id" => {}
+            _ if key.starts_with('_') => {
+                additional.insert(key[1..].to_owned(), value);
+            }
+            _ => {
+                event.insert(key, value);
+            }
+        }
+    }
+    if !event.contains_key("message") {
+        return Err("a gelf message needs a short_message".to_owned());
+    }
+    for (key, value) in additional {
+        event.entry(key).or_insert(value);
+    }
+    event.insert("time".to_owned(), json!(timestamp::canonical(&time.unwrap_or_else(Utc::now))));
+    event.insert("_source".to_owned(), json!("gelf"));
+    Ok(Value::Object(event))
+}
+
+// a payload as sent over udp: gzipped, zlib compressed or plain. one that inflates past `limit`
+// bytes is refused
+pub fn inflate(payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut message = vec![];
+    let take = limit as u64 + 1;
+    match payload {
+        [0x1f, 0x8b, ..] => MultiGzDecoder::new(payload).take(take).read_to_end(&mut message)?,
+        [0x78, second, ..] if (0x7800u16 | u16::from(*second)) % 31 == 0 => ZlibDecoder::new(payload).take(take).read_to_end(&mut message)?,
+        _ => return Ok(payload.to_vec()),
+    };
+    if message.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("a message is over {} bytes inflated", limit)));
+    }
+    Ok(message)
+}
+
+// when a message's first chunk came, and the chunks so far by sequence number
+type Pending = (Instant, Vec<Option<Vec<u8>>>);
+
+// udp messages split in chunks: magic, an 8 byte message id, sequence number and count, data
+pub struct Chunks {
+    pending: HashMap<[u8; 8], Pending>,
+    // bytes held in `pending`, and the most it may hold
+    size: usize,
+    limit: usize,
+}
+
+impl Default for Chunks {
+    fn default() -> Chunks {
+        Chunks::with_limit(MAX_PENDING)
+    }
+}
+
+fn size(chunks: &[Option<Vec<u8>>]) -> usize {
+    chunks.iter().flatten().map(Vec::len).sum()
+}
+
+impl Chunks {
+    pub fn with_limit(limit: usize) -> Chunks {
+        Chunks {
+            pending: HashMap::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    // the payload a datagram completes, if any; datagrams that aren't chunks are payloads
+    pub fn add(&mut self, datagram: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let mut expired = 0;
+        self.pending.retain(|_, (first, chunks)| {
+            let keep = now.saturating_duration_since(*first) < CHUNK_TIMEOUT;
+            if !keep {
+                expired += size(chunks);
+            }
+            keep
+        });
+        self.size -= expired;
+        if !datagram.starts_with(&CHUNK_MAGIC) {
+            return Some(datagram.to_vec());
+        }
+        if datagram.len() < 12 {
+            return None;
+        }
+        let mut id = [0; 8];
+        id.copy_from_slice(&datagram[2..10]);
+        let (sequence, count) = (datagram[10] as usize, datagram[11] as usize);
+        if count == 0 || count > MAX_CHUNKS || sequence >= count {
+            return None;
+        }
+        let (_, chunks) = self.pending.entry(id).or_insert_with(|| (now, vec![None; count]));
+        if chunks.len() != count {
+            return None;
+        }
+        let data = datagram[12..].to_vec();
+        self.size += data.len();
+        if let Some(replaced) = chunks[sequence].replace(data) {
+            self.size -= replaced.len();
+        }
+        if chunks.iter().any(Option::is_none) {
+            self.evict(id);
+            return None;
+        }
+        let (_, chunks) = self.pending.remove(&id)?;
+        self.size -= size(&chunks);
+        Some(chunks.into_iter().flatten().flatten().collect())
+    }
+
+    // drops the oldest unfinished messages, but for `keep`, until the rest fit
+    fn evict(&mut self, keep: [u8; 8]) {
+        while self.size > self.limit {
+            let oldest = self
+                .pending
+                .iter()
+                .filter(|(id, _)| **id != keep)
+                .min_by_key(|(_, (first, _))| *first)
+                .map(|(id, _)| *id);
+            let id = oldest.unwrap_or(keep);
+            if let Some((_, chunks)) = self.pending.remove(&id) {
+                self.size -= size(&chunks);
+            }
+        }
+    }
+}
+
+// tcp messages are uncompressed and end with a null byte. they are stored a batch at a time:
+// what the client has already sent, up to `MAX_BATCH`
+fn serve(stream: TcpStream, store: &Store) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut batch = vec![];
+    loop {
+        let mut message = vec![];
+        let take = MAX_MESSAGE as u64 + 1;
+        let done = (&mut reader).take(take).read_until(0, &mut message).map_err(|e| e.to_string())? == 0;
+        // a message too long to find its end drops the connection, once what came before is stored
+        let too_long = message.last() != Some(&0) && message.len() > MAX_MESSAGE;
+        if message.last() == Some(&0) {
+            message.pop();
+        }
+        if !too_long && !message.iter().all(u8::is_ascii_whitespace) {
+            // one bad message doesn't take the rest of the connection with it
+            match event(&message) {
+                Ok(event) => batch.push(event),
+                Err(e) => eprintln!("gelf: {}", e),
+            }
+        }
+        if !batch.is_empty() && (done || too_long || batch.len() >= MAX_BATCH || reader.buffer().is_empty()) {
+            store.append_waiting(&batch).map_err(|e| e.to_string())?;
+            batch.clear();
+        }
+        if too_long {
+            return Err(format!("a message is over {} bytes", MAX_MESSAGE));
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+pub fn listen_tcp(address: &str, store: Arc<Store>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let bound = listener.local_addr()?;
+    thread::spawn(move || serve_clients(listener, store, "gelf", serve));
+    Ok(bound)
+}
+
+pub fn listen_udp(address: &str, store: Arc<Store>) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(address)?;
+    let bound = socket.local_addr()?;
+    let mut chunks = Chunks::default();
+    receive_datagrams(socket, store, "gelf", move |datagram| match chunks.add(datagram, Instant::now()) {
+        Some(payload) => {
+            let message = inflate(&payload, MAX_MESSAGE).map_err(|e| e.to_string())?;
+            event(&message).map(Some)
+        }
+        None => Ok(None),
+    });
+    Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event, inflate, listen_tcp, Chunks, CHUNK_TIMEOUT};
+    use crate::ingest::Store;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use serde_json::json;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn maps_additional_fields() {
+        let message = br#"{"version": "1.1", "host": "swarm-1", "short_message": "baking", "timestamp": 1590832287.5,
+            "level": 3, "_container_name": "oven", "_host": "not this one", "_id": "dropped"}"#;
+        assert_eq!(
+            json!({
+                "version": "1.1",
+                "host": "swarm-1",
+                "message": "baking",
+                "level": 3,
+                "severity": "err",
+                "container_name": "oven",
+                "time": "2020-05-30T09:51:27.500000000Z",
+                "_source": "gelf",
+            }),
+            event(message).unwrap()
+        );
+        assert!(event(br#"{"host": "swarm-1"}"#).is_err());
+    }
+
+    #[test]
+    fn inflates_gzip_and_zlib() {
+        let message = br#"{"short_message": "hi"}"#;
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(message).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(message).unwrap();
+        for payload in &[gzip.finish().unwrap(), zlib.finish().unwrap(), message.to_vec()] {
+            assert_eq!(message.to_vec(), inflate(payload, 64).unwrap());
+        }
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+        bomb.write_all(&[b' '; 4096]).unwrap();
+        assert!(inflate(&bomb.finish().unwrap(), 1024).is_err());
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let chunk = |id: u8, sequence: u8, data: &[u8]| {
+            let mut datagram = vec![0x1e, 0x0f, id, 0, 0, 0, 0, 0, 0, 0, sequence, 2];
+            datagram.extend_from_slice(data);
+            datagram
+        };
+        let now = Instant::now();
+        let mut chunks = Chunks::default();
+        assert_eq!(None, chunks.add(&chunk(1, 1, b"lo"), now));
+        assert_eq!(None, chunks.add(&chunk(2, 0, b"lost"), now));
+        assert_eq!(Some(b"hello".to_vec()), chunks.add(&chunk(1, 0, b"hel"), now));
+        assert_eq!(None, chunks.add(&chunk(2, 1, b" in time"), now + CHUNK_TIMEOUT));
+        assert_eq!(Some(b"plain".to_vec()), chunks.add(b"plain", now));
+    }
+
+    #[test]
+    fn evicts_the_oldest_unfinished_messages() {
+        let chunk = |id: u8, data: &[u8]| {
+            let mut datagram = vec![0x1e, 0x0f, id, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+            datagram.extend_from_slice(data);
+            datagram
+        };
+        let now = Instant::now();
+        let mut chunks = Chunks::with_limit(10);
+        chunks.add(&chunk(1, b"aaaa"), now);
+        chunks.add(&chunk(2, b"bbbb"), now + Duration::from_millis(1));
+        chunks.add(&chunk(3, b"cccc"), now + Duration::from_millis(2));
+        assert_eq!(8, chunks.size);
+        assert!(!chunks.pending.contains_key(&[1, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(chunks.pending.contains_key(&[3, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn skips_bad_tcp_messages_and_keeps_the_rest() {
+        let dir = std::env::temp_dir().join(format!("still-gelf-{}", std::process::id()));
+        let address = listen_tcp("127.0.0.1:0", Arc::new(Store::new(dir.clone()))).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        let message = |text: &str| format!(r#"{{"version": "1.1", "host": "h", "short_message": "{}", "timestamp": 1590832287}}"#, text);
+        client.write_all(format!("{}\0not json\0{}\0", message("one"), message("two")).as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        // the connection is closed once everything it sent is stored
+        client.read_to_end(&mut vec![]).unwrap();
+        assert_eq!(2, fs::read_to_string(dir.join("2020-05-30.log")).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,659 @@
+use crate::timestamp;
+use chrono::Utc;
+use flate2::read::MultiGzDecoder;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the events of a batch that couldn't be taken: their position in it and why
+pub type Rejected = Vec<(usize, String)>;
+
+// starts receiving events on an address in the background, into the store; the bound address back
+pub type Listen = fn(&str, Arc<Store>) -> io::Result<SocketAddr>;
+
+// the most clients a tcp listener serves at once; past that new connections are closed right away
+const MAX_CLIENTS: usize = 256;
+
+// a client being served, counted until its thread is done however it ends
+struct Client(Arc<AtomicUsize>);
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// the most events a connection or a burst of datagrams puts in one batch
+pub const MAX_BATCH: usize = 1000;
+// batches of datagrams waiting for the store; past that they are dropped, as the network would
+const QUEUED_BATCHES: usize = 16;
+
+// receives datagrams in the background a burst at a time, so that what arrived together is
+// stored as one batch. the store is written on a thread of its own: a full buffer must not stop
+// the socket from being read. `decode` makes an event of a datagram, when it completes one
+pub fn receive_datagrams<D>(socket: UdpSocket, store: Arc<Store>, name: &'static str, mut decode: D)
+where
+    D: FnMut(&[u8]) -> Result<Option<Value>, String> + Send + 'static,
+{
+    let (batches, queued) = mpsc::sync_channel::<Vec<Value>>(QUEUED_BATCHES);
+    thread::spawn(move || {
+        for batch in queued {
+            if let Err(e) = store.append_waiting(&batch) {
+                eprintln!("{}: {}", name, e);
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut buffer = [0; 65536];
+        loop {
+            let mut batch = vec![];
+            // the first datagram is waited for, the rest of the burst is what's already there
+            let mut waiting = true;
+            while batch.len() < MAX_BATCH {
+                if socket.set_nonblocking(!waiting).is_err() {
+                    break;
+                }
+                let length = match socket.recv_from(&mut buffer) {
+                    Ok((length, _)) => length,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        eprintln!("{}: {}", name, e);
+                        break;
+                    }
+                };
+                waiting = false;
+                match decode(&buffer[..length]) {
+                    Ok(Some(event)) => batch.push(event),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("{}: {}", name, e),
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            if let Err(TrySendError::Full(batch)) = batches.try_send(batch) {
+                eprintln!("{}: the store is behind, dropping {} messages", name, batch.len());
+            }
+        }
+    });
+}
+
+// serves each client of `listener` on a thread of its own; `name` is the protocol, for errors
+pub fn serve_clients(listener: TcpListener, store: Arc<Store>, name: &'static str, serve: fn(TcpStream, &Store) -> Result<(), String>) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming().filter_map(Result::ok) {
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("{} client {}: too many clients, closing", name, peer);
+            continue;
+        }
+        let client = Client(clients.clone());
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &store) {
+                eprintln!("{} client {}: {}", name, peer, e);
+            }
+            drop(client);
+        });
+    }
+}
+
+// what came of one batch: how many of its events were stored and why the others weren't
+#[derive(Debug, PartialEq)]
+pub struct Ack {
+    pub batch: u64,
+    pub accepted: usize,
+    pub rejected: Rejected,
+}
+
+impl Ack {
+    pub fn to_json(&self) -> Value {
+        let rejected: Vec<Value> = self
+            .rejected
+            .iter()
+            .map(|(index, message)| json!({ "index": index, "message": message }))
+            .collect();
+        json!({ "batch": self.batch, "accepted": self.accepted, "rejected": rejected })
+    }
+}
+
+// why a request body couldn't be taken: bigger than `limit` bytes once gunzipped, or unreadable
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    TooLarge(u64),
+    Invalid(String),
+}
+
+impl From<String> for BodyError {
+    fn from(message: String) -> BodyError {
+        BodyError::Invalid(message)
+    }
+}
+
+impl From<&str> for BodyError {
+    fn from(message: &str) -> BodyError {
+        BodyError::Invalid(message.to_owned())
+    }
+}
+
+// a body gunzipped if it is gzipped, reading no more than `limit` bytes out of it
+pub fn gunzip(body: &[u8], limit: u64) -> Result<Cow<[u8]>, BodyError> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Cow::Borrowed(body));
+    }
+    let mut inflated = vec![];
+    MultiGzDecoder::new(body)
+        .take(limit + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| format!("can't gunzip the body: {}", e))?;
+    if inflated.len() as u64 > limit {
+        return Err(BodyError::TooLarge(limit));
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+// a body of text, gzipped or not
+pub fn body_text(body: &[u8], limit: u64) -> Result<String, BodyError> {
+    let text = gunzip(body, limit)?;
+    String::from_utf8(text.into_owned()).map_err(|_| "the body isn't utf-8".into())
+}
+
+// a json array of events or one event per line, gzipped or not. the events that can't be
+// read come back with their position in the batch
+pub fn decode_batch(body: &[u8], limit: u64) -> Result<(Vec<Value>, Rejected), BodyError> {
+    let text = body_text(body, limit)?;
+    let items: Vec<Result<Value, String>> = if text.trim_start().starts_with('[') {
+        match serde_json::from_str::<Vec<Value>>(&text) {
+            Ok(items) => items.into_iter().map(Ok).collect(),
+            Err(e) => return Err(format!("can't read the json array: {}", e).into()),
+        }
+    } else {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+            .collect()
+    };
+    let mut events = vec![];
+    let mut rejected = vec![];
+    for (index, item) in items.into_iter().enumerate() {
+        match item {
+            Ok(event @ Value::Object(_)) => events.push(event),
+            Ok(_) => rejected.push((index, "not a json object".to_owned())),
+            Err(e) => rejected.push((index, e)),
+        }
+    }
+    Ok((events, rejected))
+}
+
+// when the write-ahead log is flushed to disk: before each batch is acknowledged, or every so
+// often, trading the last moments before a crash for throughput
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    Batch,
+    Interval(Duration),
+}
+
+impl Fsync {
+    // `batch`, or an interval as `500ms` or `2s`
+    pub fn parse(text: &str) -> Result<Fsync, String> {
+        let number = |digits: &str| digits.parse::<u64>().ok();
+        let interval = if text == "batch" {
+            return Ok(Fsync::Batch);
+        } else if let Some(millis) = text.strip_suffix("ms").and_then(number) {
+            Duration::from_millis(millis)
+        } else if let Some(seconds) = text.strip_suffix('s').and_then(number) {
+            Duration::from_secs(seconds)
+        } else {
+            return Err(format!("expected batch or an interval like 1s or 200ms, got `{}`", text));
+        };
+        Ok(Fsync::Interval(interval))
+    }
+}
+
+// how often what the log protects is synced to the day files so its segments can go
+const CHECKPOINT_EVERY: Duration = Duration::from_secs(5);
+
+// what `append` fails with while the buffer is full; callers push back on their clients
+pub fn buffer_full(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::WouldBlock
+}
+
+// a file that was just created only survives a crash once the entry in its dir is synced too
+fn sync_dirs<'p>(files: impl IntoIterator<Item = &'p PathBuf>) -> io::Result<()> {
+    let dirs: HashSet<&Path> = files.into_iter().filter_map(|file| file.parent()).collect();
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+struct Wal {
+    batches: u64,
+    // the segment being written, by number
+    segment: Option<(u64, File)>,
+    next_segment: u64,
+    // bytes in segments whose day files aren't synced yet: the buffer
+    buffered: u64,
+    // segments closed for the next checkpoint, with their size
+    sealed: Vec<(PathBuf, u64)>,
+    // day files written since the last checkpoint started
+    dirty: HashSet<PathBuf>,
+}
+
+// where ingested events are kept: one `.log` per day of event time (or of arrival, for events
+// without one) in a dir under the logs dir, so searches pick them up like any other file.
+// a batch goes to a write-ahead log in `wal/` before the day files; a checkpoint syncs the day
+// files and drops the log up to there, and what a crash left in it is replayed on `open`
+pub struct Store {
+    dir: PathBuf,
+    fsync: Fsync,
+    buffer_limit: u64,
+    // also serialises writers, so the lines of two batches never interleave
+    wal: Mutex<Wal>,
+    checkpoints: Mutex<()>,
+}
+
+impl Store {
+    pub fn new(dir: PathBuf) -> Store {
+        Store::with_wal(dir, Fsync::Batch, 256 * 1024 * 1024)
+    }
+
+    fn with_wal(dir: PathBuf, fsync: Fsync, buffer_limit: u64) -> Store {
+        Store {
+            dir,
+            fsync,
+            buffer_limit,
+            wal: Mutex::new(Wal {
+                batches: 0,
+                segment: None,
+                next_segment: 0,
+                buffered: 0,
+                sealed: vec![],
+                dirty: HashSet::new(),
+            }),
+            checkpoints: Mutex::new(()),
+        }
+    }
+
+    // the store with what the log kept replayed, checkpointing (and syncing the log, for an
+    // interval policy) in the background
+    pub fn open(dir: PathBuf, fsync: Fsync, buffer_limit: u64) -> io::Result<Arc<Store>> {
+        let store = Store::with_wal(dir, fsync, buffer_limit);
+        store.replay()?;
+        let store = Arc::new(store);
+        let weak = Arc::downgrade(&store);
+        let tick = match fsync {
+            Fsync::Interval(interval) => interval.min(CHECKPOINT_EVERY),
+            Fsync::Batch => CHECKPOINT_EVERY,
+        };
+        thread::spawn(move || {
+            let mut checkpointed = Instant::now();
+            loop {
+                thread::sleep(tick);
+                let store = match weak.upgrade() {
+                    Some(store) => store,
+                    None => return,
+                };
+                if let Err(e) = store.sync_wal() {
+                    eprintln!("ingest wal: {}", e);
+                }
+                if checkpointed.elapsed() >= CHECKPOINT_EVERY || store.buffered() > store.buffer_limit / 4 {
+                    if let Err(e) = store.checkpoint() {
+                        eprintln!("ingest checkpoint: {}", e);
+                    }
+                    checkpointed = Instant::now();
+                }
+            }
+        });
+        Ok(store)
+    }
+
+    fn wal_dir(&self) -> PathBuf {
+        self.dir.join("wal")
+    }
+
+    fn buffered(&self) -> u64 {
+        self.wal.lock().unwrap().buffered
+    }
+
+    pub fn partition(event: &Value) -> String {
+        let time = timestamp::event_time(event).unwrap_or_else(Utc::now);
+        format!("{}.log", time.format("%Y-%m-%d"))
+    }
+
+    pub fn append(&self, events: &[Value], rejected: Rejected) -> io::Result<Ack> {
+        self.append_in("", events, rejected)
+    }
+
+    // the same, in a dir of its own under the store's; `dir` is relative and made of plain names
+    pub fn append_in(&self, dir: &str, events: &[Value], rejected: Rejected) -> io::Result<Ack> {
+        self.append_each(&[(dir, events)], rejected)
+    }
+
+    // the events of several dirs as one batch: there's room in the buffer for all of them or
+    // none is written
+    pub fn append_each(&self, dirs: &[(&str, &[Value])], rejected: Rejected) -> io::Result<Ack> {
+        let mut partitions: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for (dir, events) in dirs {
+            for event in events.iter() {
+                let file = Path::new(dir).join(Store::partition(event));
+                let lines = partitions.entry(file).or_insert_with(String::new);
+                lines.push_str(&event.to_string());
+                lines.push('\n');
+            }
+        }
+        let mut wal = self.wal.lock().unwrap();
+        for (dir, _) in dirs {
+            create_dir_all(self.dir.join(dir))?;
+        }
+        // each day file's lines with where they go in it, so a replay can tell if they made it
+        let mut records = String::new();
+        let mut writes = vec![];
+        for (file, lines) in partitions {
+            let path = self.dir.join(&file);
+            let offset = fs::metadata(&path).map_or(0, |m| m.len());
+            records.push_str(&json!({ "file": file, "offset": offset, "lines": lines }).to_string());
+            records.push('\n');
+            writes.push((path, offset, lines));
+        }
+        let size = records.len() as u64;
+        // a batch bigger than the whole buffer still goes in, on its own
+        if wal.buffered > 0 && wal.buffered + size > self.buffer_limit {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "the ingest buffer is full, retry later"));
+        }
+        if wal.segment.is_none() {
+            create_dir_all(self.wal_dir())?;
+            let number = wal.next_segment;
+            let path = self.wal_dir().join(format!("{:020}.wal", number));
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            sync_dirs(&[path])?;
+            wal.segment = Some((number, file));
+            wal.next_segment += 1;
+        }
+        let (_, segment) = wal.segment.as_mut().unwrap();
+        let start = segment.metadata()?.len();
+        if let Err(e) = self.write_batch(segment, &records, &writes) {
+            // a torn record or line would be glued to the next one, and a retry would write the
+            // rest again: the batch is taken back whole. when even that fails the segment is
+            // sealed, and what it holds is left to the checkpoint or a replay
+            let truncate = |path: &PathBuf, length: u64| match fs::metadata(path) {
+                Ok(m) if m.len() > length => OpenOptions::new().write(true).open(path)?.set_len(length),
+                _ => Ok(()),
+            };
+            let rolled_back = segment.set_len(start).is_ok() && writes.iter().all(|(path, offset, _)| truncate(path, *offset).is_ok());
+            if !rolled_back {
+                self.seal(&mut wal)?;
+            }
+            return Err(e);
+        }
+        wal.buffered += size;
+        wal.dirty.extend(writes.into_iter().map(|(path, _, _)| path));
+        wal.batches += 1;
+        Ok(Ack {
+            batch: wal.batches,
+            accepted: dirs.iter().map(|(_, events)| events.len()).sum(),
+            rejected,
+        })
+    }
+
+    fn write_batch(&self, segment: &mut File, records: &str, writes: &[(PathBuf, u64, String)]) -> io::Result<()> {
+        segment.write_all(records.as_bytes())?;
+        if self.fsync == Fsync::Batch {
+            segment.sync_data()?;
+        }
+        for (path, _, lines) in writes {
+            OpenOptions::new().create(true).append(true).open(path)?.write_all(lines.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // closes the current segment, if any: it goes at the next checkpoint
+    fn seal(&self, wal: &mut Wal) -> io::Result<()> {
+        if let Some((number, segment)) = wal.segment.take() {
+            segment.sync_data()?;
+            let size = segment.metadata()?.len();
+            wal.sealed.push((self.wal_dir().join(format!("{:020}.wal", number)), size));
+        }
+        Ok(())
+    }
+
+    // the same, waiting for room in the buffer: for clients that are slowed down by not being read
+    pub fn append_waiting(&self, events: &[Value]) -> io::Result<Ack> {
+        loop {
+            match self.append(events, vec![]) {
+                Err(ref e) if buffer_full(e) => thread::sleep(Duration::from_millis(100)),
+                result => return result,
+            }
+        }
+    }
+
+    fn sync_wal(&self) -> io::Result<()> {
+        match &self.wal.lock().unwrap().segment {
+            Some((_, segment)) => segment.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    // syncs the day files written so far and drops the segments that covered them. writers
+    // carry on in a new segment meanwhile
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let _checkpoint = self.checkpoints.lock().unwrap();
+        let dirty = {
+            let mut wal = self.wal.lock().unwrap();
+            self.seal(&mut wal)?;
+            wal.dirty.drain().collect::<Vec<PathBuf>>()
+        };
+        for path in &dirty {
+            File::open(path)?.sync_all()?;
+        }
+        // the day files created since the last checkpoint have to be there before the log goes
+        sync_dirs(&dirty)?;
+        let mut wal = self.wal.lock().unwrap();
+        for (segment, size) in wal.sealed.split_off(0) {
+            fs::remove_file(&segment)?;
+            wal.buffered -= size.min(wal.buffered);
+        }
+        Ok(())
+    }
+
+    // puts back what the segments left by a crash hold and the day files don't: lines already at
+    // their offset are skipped, a torn write is cut off and written again
+    fn replay(&self) -> io::Result<()> {
+        let mut segments: Vec<PathBuf> = match fs::read_dir(self.wal_dir()) {
+            Ok(entries) => entries.filter_map(Result::ok).map(|entry| entry.path()).collect(),
+            Err(_) => return Ok(()),
+        };
+        segments.retain(|path| path.extension().map_or(false, |e| e == "wal"));
+        segments.sort();
+        let mut written = HashSet::new();
+        for segment in &segments {
+            // a record torn by the crash was never acknowledged, so it isn't replayed. it may
+            // have been cut in the middle of a character, too
+            for line in fs::read(segment)?.split(|byte| *byte == b'\n') {
+                let record: Value = match serde_json::from_slice(line) {
+                    Ok(record) => record,
+                    Err(_) => continue,
+                };
+                let (file, offset, lines) = match (record["file"].as_str(), record["offset"].as_u64(), record["lines"].as_str()) {
+                    (Some(file), Some(offset), Some(lines)) => (file, offset, lines),
+                    _ => continue,
+                };
+                let path = self.dir.join(file);
+                let length = fs::metadata(&path).map_or(0, |m| m.len());
+                if length >= offset + lines.len() as u64 {
+                    continue;
+                }
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+                let mut day = OpenOptions::new().create(true).write(true).open(&path)?;
+                day.set_len(offset.min(length))?;
+                day.seek(SeekFrom::End(0))?;
+                day.write_all(lines.as_bytes())?;
+                written.insert(path);
+            }
+        }
+        for path in &written {
+            File::open(path)?.sync_all()?;
+        }
+        sync_dirs(&written)?;
+        for segment in &segments {
+            fs::remove_file(segment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buffer_full, decode_batch, receive_datagrams, BodyError, Fsync, Store};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+    use std::fs;
+    use std::io::Write;
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn decodes_ndjson_arrays_and_gzip() {
+        let ndjson = "{\"a\":1}\n\nnot json\n[1]\n{\"a\":2}\n";
+        let (events, rejected) = decode_batch(ndjson.as_bytes(), 1024).unwrap();
+        assert_eq!(vec![json!({"a": 1}), json!({"a": 2})], events);
+        assert_eq!(vec![1, 2], rejected.iter().map(|(index, _)| *index).collect::<Vec<usize>>());
+
+        let (events, rejected) = decode_batch(br#"[{"a": 1}, 2]"#, 1024).unwrap();
+        assert_eq!((vec![json!({"a": 1})], 1), (events, rejected.len()));
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(ndjson.as_bytes()).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(2, decode_batch(&gz, 1024).unwrap().0.len());
+        // the limit holds for what comes out of the gzip, not just for what went over the wire
+        assert_eq!(Err(BodyError::TooLarge(16)), decode_batch(&gz, 16));
+
+        assert!(decode_batch(b"[{\"a\": 1}", 1024).is_err());
+    }
+
+    #[test]
+    fn appends_by_day_of_event_time() {
+        let dir = std::env::temp_dir().join(format!("still-ingest-{}", std::process::id()));
+        let store = Store::new(dir.join("ingest"));
+        let events = vec![
+            json!({"time": "2020-05-30T23:59:59Z", "n": 1}),
+            json!({"time": "2020-05-31T00:00:01Z", "n": 2}),
+            json!({"date": 1_590_883_200.5, "n": 3}),
+        ];
+        let ack = store.append(&events, vec![]).unwrap();
+        assert_eq!((1, 3), (ack.batch, ack.accepted));
+        assert_eq!(2, store.append(&events[..1], vec![]).unwrap().batch);
+        assert_eq!(
+            "{\"n\":1,\"time\":\"2020-05-30T23:59:59Z\"}\n{\"n\":1,\"time\":\"2020-05-30T23:59:59Z\"}\n",
+            fs::read_to_string(dir.join("ingest/2020-05-30.log")).unwrap()
+        );
+        assert_eq!(2, fs::read_to_string(dir.join("ingest/2020-05-31.log")).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stores_datagrams_off_the_receive_thread() {
+        let dir = std::env::temp_dir().join(format!("still-datagrams-{}", std::process::id()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        receive_datagrams(socket, Arc::new(Store::new(dir.clone())), "test", |datagram| match datagram {
+            b"skip" => Ok(None),
+            b"bad" => Err("bad".to_owned()),
+            _ => Ok(Some(json!({"time": "2020-05-30T12:00:00Z", "m": String::from_utf8_lossy(datagram)}))),
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in &["a", "skip", "bad", "b", "c"] {
+            client.send_to(datagram.as_bytes(), address).unwrap();
+        }
+        let log = dir.join("2020-05-30.log");
+        for _ in 0..50 {
+            if fs::read_to_string(&log).map_or(0, |log| log.lines().count()) == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(3, fs::read_to_string(&log).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pushes_back_until_a_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("still-wal-{}", std::process::id()));
+        let store = Store::with_wal(dir.clone(), Fsync::Interval(Duration::from_secs(1)), 200);
+        let event = json!({"time": "2020-05-30T09:51:27Z", "log": "x".repeat(50)});
+        store.append(&[event.clone()], vec![]).unwrap();
+        assert!(buffer_full(&store.append(&[event.clone(), event.clone()], vec![]).unwrap_err()));
+        store.checkpoint().unwrap();
+        assert_eq!(0, fs::read_dir(dir.join("wal")).unwrap().count());
+        store.append(&[event.clone(), event.clone()], vec![]).unwrap();
+        assert_eq!(3, fs::read_to_string(dir.join("2020-05-30.log")).unwrap().lines().count());
+
+        // a batch across dirs is refused whole
+        let events = [event.clone(), event];
+        assert!(buffer_full(&store.append_each(&[("indices/a", &events[..1]), ("indices/b", &events)], vec![]).unwrap_err()));
+        assert!(!dir.join("indices/a/2020-05-30.log").exists());
+        store.checkpoint().unwrap();
+        assert_eq!(3, store.append_each(&[("indices/a", &events[..1]), ("indices/b", &events)], vec![]).unwrap().accepted);
+        assert_eq!(2, fs::read_to_string(dir.join("indices/b/2020-05-30.log")).unwrap().lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_what_a_crash_left_out() {
+        let dir = std::env::temp_dir().join(format!("still-replay-{}", std::process::id()));
+        fs::create_dir_all(dir.join("wal")).unwrap();
+        fs::write(dir.join("2020-05-30.log"), "{\"n\":1}\n{\"n\":2").unwrap();
+        let segment = [
+            json!({"file": "2020-05-30.log", "offset": 0, "lines": "{\"n\":1}\n"}).to_string(),
+            json!({"file": "2020-05-30.log", "offset": 8, "lines": "{\"n\":2}\n"}).to_string(),
+            json!({"file": "nginx/2020-05-31.log", "offset": 0, "lines": "{\"n\":3}\n"}).to_string(),
+            "{\"file\": \"torn".to_owned(),
+        ];
+        fs::write(dir.join("wal/00000000000000000000.wal"), segment.join("\n")).unwrap();
+        Store::open(dir.clone(), Fsync::Batch, 1024).unwrap();
+        assert_eq!("{\"n\":1}\n{\"n\":2}\n", fs::read_to_string(dir.join("2020-05-30.log")).unwrap());
+        assert_eq!("{\"n\":3}\n", fs::read_to_string(dir.join("nginx/2020-05-31.log")).unwrap());
+        assert_eq!(0, fs::read_dir(dir.join("wal")).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_a_segment_cut_mid_character() {
+        let dir = std::env::temp_dir().join(format!("still-replay-torn-{}", std::process::id()));
+        fs::create_dir_all(dir.join("wal")).unwrap();
+        let mut segment = json!({"file": "2020-05-30.log", "offset": 0, "lines": "{\"m\":\"é\"}\n"}).to_string().into_bytes();
+        segment.push(b'\n');
+        let torn = json!({"file": "2020-05-30.log", "offset": 11, "lines": "{\"m\":\"é\"}\n"}).to_string();
+        segment.extend_from_slice(&torn.as_bytes()[..torn.find('é').unwrap() + 1]);
+        fs::write(dir.join("wal/00000000000000000000.wal"), segment).unwrap();
+        Store::open(dir.clone(), Fsync::Batch, 1024).unwrap();
+        assert_eq!("{\"m\":\"é\"}\n", fs::read_to_string(dir.join("2020-05-30.log")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_fsync_policies() {
+        assert_eq!(Ok(Fsync::Batch), Fsync::parse("batch"));
+        assert_eq!(Ok(Fsync::Interval(Duration::from_millis(200))), Fsync::parse("200ms"));
+        assert_eq!(Ok(Fsync::Interval(Duration::from_secs(2))), Fsync::parse("2s"));
+        assert!(Fsync::parse("sometimes").is_err());
+    }
+}
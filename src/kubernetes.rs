@@ -0,0 +1,83 @@
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+lazy_static! {
+    // /var/log/containers/<pod>_<namespace>_<container>-<container id>.log, as named by the kubelet
+    static ref CONTAINER_LOG: Regex = Regex::new(
+        r"^(?P<pod>[a-z0-9]([-a-z0-9]*[a-z0-9])?(\.[a-z0-9]([-a-z0-9]*[a-z0-9])?)*)_(?P<namespace>[^_]+)_(?P<container>.+)-(?P<id>[a-z0-9]{64})\.log$"
+    )
+    .unwrap();
+    // /var/log/pods/<namespace>_<pod>_<uid>/<container>/<restart>.log, rotations included
+    static ref POD_LOG: Regex =
+        Regex::new(r"(?:^|/)pods/(?P<namespace>[^_/]+)_(?P<pod>[^_/]+)_(?P<uid>[^_/]+)/(?P<container>[^/]+)/[0-9]+\.log[^/]*$").unwrap();
+}
+
+// the `kubernetes` fields fluentd would have added to the events of this file, when its path
+// follows one of the kubelet's layouts
+pub fn metadata(path: &Path) -> Option<Value> {
+    let file_name = path.file_name()?.to_string_lossy();
+    if let Some(cap) = CONTAINER_LOG.captures(&file_name) {
+        return Some(json!({
+            "pod_name": &cap["pod"],
+            "namespace_name": &cap["namespace"],
+            "container_name": &cap["container"],
+            "docker_id": &cap["id"],
+        }));
+    }
+    let path = path.to_string_lossy();
+    POD_LOG.captures(&path).map(|cap| {
+        json!({
+            "pod_name": &cap["pod"],
+            "namespace_name": &cap["namespace"],
+            "container_name": &cap["container"],
+            "pod_id": &cap["uid"],
+        })
+    })
+}
+
+// the fields to attach to every event of the file at `path`
+pub fn fields(path: &Path) -> Map<String, Value> {
+    let mut fields = Map::new();
+    if let Some(metadata) = metadata(path) {
+        fields.insert("kubernetes".to_owned(), metadata);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::metadata;
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn reads_container_log_names() {
+        let id = "5ab41e0a5c5182c83c26b01c9bdf700605a3c49421f260c6ac8fa54d16fd4a6c";
+        let path = format!("/var/log/containers/netronner-ui-656c94c66b-bwvx2_netronner_netronner-ui-{}.log", id);
+        assert_eq!(
+            Some(json!({
+                "pod_name": "netronner-ui-656c94c66b-bwvx2",
+                "namespace_name": "netronner",
+                "container_name": "netronner-ui",
+                "docker_id": id,
+            })),
+            metadata(Path::new(&path))
+        );
+    }
+
+    #[test]
+    fn reads_pod_log_dirs() {
+        let path = Path::new("/var/log/pods/kube-system_csi-do-node-fz9dw_fca56326-9a99-4f65-bb78-b84ac98d1d45/csi-do-plugin/0.log");
+        assert_eq!(
+            Some(json!({
+                "pod_name": "csi-do-node-fz9dw",
+                "namespace_name": "kube-system",
+                "container_name": "csi-do-plugin",
+                "pod_id": "fca56326-9a99-4f65-bb78-b84ac98d1d45",
+            })),
+            metadata(path)
+        );
+        assert_eq!(None, metadata(Path::new("/var/log/syslog.log")));
+    }
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Error, Formatter};
+
+// a bad escape inside a quoted literal, located in bytes of the whole query
+#[derive(Clone, Debug, PartialEq)]
+pub struct LiteralError {
+    pub offset: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", self.message)
+    }
+}
+
+// body of a "double quoted" literal, without the quotes. `offset` is where the body starts in the query.
+// supports \" \' \\ \n \r \t \0 and \u{...}, any other escape is left as written; borrows when
+// there is nothing to unescape
+pub fn unescape(body: &str, offset: usize) -> Result<Cow<str>, LiteralError> {
+    if !body.contains('\\') {
+        return Ok(Cow::Borrowed(body));
+    }
+    let mut unescaped = String::with_capacity(body.len());
+    let mut chars = body.char_indices();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let error = |end: usize, message: String| LiteralError {
+            offset: offset + start,
+            end: offset + end,
+            message,
+        };
+        let (escape_start, escape) = chars
+            .next()
+            .ok_or_else(|| error(body.len(), "unterminated escape sequence".to_owned()))?;
+        match escape {
+            '"' | '\'' | '\\' => unescaped.push(escape),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            't' => unescaped.push('\t'),
+            '0' => unescaped.push('\0'),
+            'u' => {
+                let rest = &body[escape_start + 1..];
+                let close = match (rest.starts_with('{'), rest.find('}')) {
+                    (true, Some(close)) => close,
+                    _ => return Err(error(escape_start + 1, r"expected \u{...}".to_owned())),
+                };
+                let end = escape_start + 1 + close + 1;
+                let hex = &rest[1..close];
+                let decoded = if hex.is_empty() || hex.len() > 6 {
+                    None
+                } else {
+                    u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32)
+                };
+                match decoded {
+                    Some(decoded) => unescaped.push(decoded),
+                    None => return Err(error(end, format!("invalid unicode escape {:?}", &body[start..end]))),
+                }
+                for _ in 0..close + 1 {
+                    chars.next();
+                }
+            }
+            // kept with its backslash, so regexes like "\d+" read as they did before escapes
+            other => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+        }
+    }
+    Ok(Cow::Owned(unescaped))
+}
+
+// body of a 'single quoted' literal: only \' is an escape, every other backslash is kept
+// as-is so regexes like '\d+' read the same as they would anywhere else
+pub fn unescape_single_quoted(body: &str) -> Cow<str> {
+    if body.contains(r"\'") {
+        Cow::Owned(body.replace(r"\'", "'"))
+    } else {
+        Cow::Borrowed(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, unescape_single_quoted};
+    use std::borrow::Cow;
+
+    #[test]
+    fn borrows_when_nothing_to_unescape() {
+        assert_eq!(Ok(Cow::Borrowed("GET /assets")), unescape("GET /assets", 0));
+        assert_eq!(Cow::Borrowed(r"(\d+)"), unescape_single_quoted(r"(\d+)"));
+    }
+
+    #[test]
+    fn unescapes_double_quoted() {
+        assert_eq!(r#""GET /assets"#, unescape(r#"\"GET /assets"#, 0).unwrap());
+        assert_eq!("a\tb\\c\n", unescape(r"a\tb\\c\n", 0).unwrap());
+        assert_eq!("caf\u{e9} \u{1f35e}", unescape(r"caf\u{e9} \u{1F35E}", 0).unwrap());
+    }
+
+    #[test]
+    fn keeps_unknown_escapes() {
+        assert_eq!(r"(\d+) \. \q", unescape(r"(\d+) \. \q", 0).unwrap());
+    }
+
+    #[test]
+    fn single_quoted_only_unescapes_quotes() {
+        assert_eq!(r"it's \d", unescape_single_quoted(r"it\'s \d"));
+    }
+
+    #[test]
+    fn reports_bad_escapes_in_query_offsets() {
+        let error = unescape(r"ab\", 10).unwrap_err();
+        assert_eq!((12, 13), (error.offset, error.end));
+        let error = unescape(r"ab\u{110000}", 10).unwrap_err();
+        assert_eq!((12, 22), (error.offset, error.end));
+    }
+}
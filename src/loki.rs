@@ -0,0 +1,182 @@
+use crate::ingest::{body_text, BodyError, Rejected};
+use crate::timestamp;
+use chrono::{DateTime, TimeZone, Utc};
+use prost::Message;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+lazy_static! {
+    static ref LABEL: Regex = Regex::new(r#"([a-zA-Z_][a-zA-Z0-9_]*)\s*=\s*"((?:[^"\\]|\\.)*)""#).unwrap();
+}
+
+// the messages of loki's push.proto, as promtail sends them
+#[derive(Clone, PartialEq, Message)]
+struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    labels: String,
+    #[prost(message, repeated, tag = "2")]
+    entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    line: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+// `{app="nginx", namespace="kitchen"}`, the way labels travel in the protobuf body
+fn parse_labels(text: &str) -> Map<String, Value> {
+    LABEL
+        .captures_iter(text)
+        .map(|cap| (cap[1].to_owned(), json!(cap[2].replace("\\\"", "\"").replace("\\\\", "\\"))))
+        .collect()
+}
+
+// a line as an event: a json object as it is, anything else `_raw`; the stream's labels are
+// added as fields and the entry's time is kept as `_time`
+fn event(labels: &Map<String, Value>, time: DateTime<Utc>, line: &str) -> Value {
+    let mut event = match serde_json::from_str(line) {
+        Ok(Value::Object(event)) => event,
+        _ => {
+            let mut event = Map::new();
+            event.insert("_raw".to_owned(), json!(line));
+            event
+        }
+    };
+    for (name, value) in labels {
+        event.entry(name.as_str()).or_insert_with(|| value.clone());
+    }
+    event.insert("_time".to_owned(), json!(timestamp::canonical(&time)));
+    event.insert("_source".to_owned(), json!("loki"));
+    Value::Object(event)
+}
+
+fn nanoseconds(text: &str) -> Option<DateTime<Utc>> {
+    let nanos: i64 = text.parse().ok()?;
+    Utc.timestamp_opt(nanos.div_euclid(1_000_000_000), nanos.rem_euclid(1_000_000_000) as u32).single()
+}
+
+// `{"streams": [{"stream": {labels}, "values": [["<unix nanoseconds>", "line"]...]}]}`
+fn decode_json(body: &[u8], limit: u64) -> Result<(Vec<Value>, Rejected), BodyError> {
+    let text = body_text(body, limit)?;
+    let push: Value = serde_json::from_str(&text).map_err(|e| format!("can't read the push request: {}", e))?;
+    let streams = push["streams"].as_array().ok_or("`streams` should be a list")?;
+    let mut events = vec![];
+    let mut rejected = vec![];
+    let mut index = 0;
+    for stream in streams {
+        let labels = stream["stream"].as_object().cloned().unwrap_or_default();
+        for value in stream["values"].as_array().map_or(&[][..], Vec::as_slice) {
+            let time = value[0].as_str().and_then(nanoseconds);
+            match (time, value[1].as_str()) {
+                (Some(time), Some(line)) => events.push(event(&labels, time, line)),
+                (None, _) => rejected.push((index, "the time should be a string of unix nanoseconds".to_owned())),
+                (_, None) => rejected.push((index, "the line should be a string".to_owned())),
+            }
+            index += 1;
+        }
+    }
+    Ok((events, rejected))
+}
+
+// a snappy compressed `PushRequest`; snappy says how big it is before anything is decompressed
+fn decode_protobuf(body: &[u8], limit: u64) -> Result<(Vec<Value>, Rejected), BodyError> {
+    let length = snap::raw::decompress_len(body).map_err(|e| format!("can't unsnappy the body: {}", e))?;
+    if length as u64 > limit {
+        return Err(BodyError::TooLarge(limit));
+    }
+    let raw = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| format!("can't unsnappy the body: {}", e))?;
+    let push = PushRequest::decode(raw.as_slice()).map_err(|e| format!("can't read the push request: {}", e))?;
+    let mut events = vec![];
+    let mut rejected = vec![];
+    let mut index = 0;
+    for stream in push.streams {
+        let labels = parse_labels(&stream.labels);
+        for entry in stream.entries {
+            let time = entry
+                .timestamp
+                .and_then(|t| Utc.timestamp_opt(t.seconds, t.nanos as u32).single());
+            match time {
+                Some(time) => events.push(event(&labels, time, &entry.line)),
+                None => rejected.push((index, "an entry without a valid timestamp".to_owned())),
+            }
+            index += 1;
+        }
+    }
+    Ok((events, rejected))
+}
+
+// the body of a `POST /loki/api/v1/push`, json or snappy protobuf as its content type says.
+// entries are numbered across streams in the rejections
+pub fn decode_push(body: &[u8], protobuf: bool, limit: u64) -> Result<(Vec<Value>, Rejected), BodyError> {
+    if protobuf {
+        decode_protobuf(body, limit)
+    } else {
+        decode_json(body, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_push, BodyError, EntryAdapter, PushRequest, StreamAdapter, Timestamp};
+    use prost::Message;
+    use serde_json::json;
+
+    #[test]
+    fn decodes_json_pushes() {
+        let body = r#"{"streams": [{"stream": {"app": "oven", "namespace": "kitchen"},
+            "values": [["1590832287500000000", "baking"], ["soon", "x"], ["1590832288000000000", "{\"app\": \"own\", \"n\": 1}"]]}]}"#;
+        let (events, rejected) = decode_push(body.as_bytes(), false, 1024).unwrap();
+        assert_eq!(
+            vec![
+                json!({"_raw": "baking", "app": "oven", "namespace": "kitchen", "_time": "2020-05-30T09:51:27.500000000Z", "_source": "loki"}),
+                json!({"app": "own", "n": 1, "namespace": "kitchen", "_time": "2020-05-30T09:51:28.000000000Z", "_source": "loki"}),
+            ],
+            events
+        );
+        assert_eq!(vec![1], rejected.iter().map(|(index, _)| *index).collect::<Vec<usize>>());
+        assert!(decode_push(b"{\"streams\": 1}", false, 1024).is_err());
+    }
+
+    #[test]
+    fn decodes_snappy_protobuf_pushes() {
+        let push = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: r#"{app="oven", quote="say \"hi\""}"#.to_owned(),
+                entries: vec![EntryAdapter {
+                    timestamp: Some(Timestamp { seconds: 1_590_832_287, nanos: 500_000_000 }),
+                    line: "baking".to_owned(),
+                }],
+            }],
+        };
+        let mut raw = vec![];
+        push.encode(&mut raw).unwrap();
+        let body = snap::raw::Encoder::new().compress_vec(&raw).unwrap();
+        let (events, rejected) = decode_push(&body, true, 1024).unwrap();
+        assert_eq!(
+            vec![json!({"_raw": "baking", "app": "oven", "quote": "say \"hi\"", "_time": "2020-05-30T09:51:27.500000000Z", "_source": "loki"})],
+            events
+        );
+        assert!(rejected.is_empty());
+        assert_eq!(Err(BodyError::TooLarge(8)), decode_push(&body, true, 8).map(|_| ()));
+        assert!(decode_push(b"not snappy", true, 1024).is_err());
+    }
+}
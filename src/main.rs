@@ -5,30 +5,69 @@ extern crate rocket;
 extern crate rocket_contrib;
 #[macro_use]
 extern crate lalrpop_util;
+#[macro_use]
+extern crate lazy_static;
 extern crate regex;
 extern crate serde;
 extern crate serde_json;
 use regex::Regex;
 use serde_json::json;
 use serde_json::Value;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::File;
-use std::fs::read_dir;
 use std::io::prelude::*;
-use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use rocket::data::Data;
 use rocket::fairing::AdHoc;
-use rocket::response::NamedFile;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, NamedFile, Responder, Response};
+use rocket::response::status::{BadRequest, Custom};
 use rocket::State;
 use rocket_contrib::json::Json;
 
 lalrpop_mod!(pub search);
 
 mod ast;
+mod decoder;
+mod discovery;
+mod elasticsearch;
+mod field_path;
+mod forward;
+mod gelf;
+mod ingest;
+mod kubernetes;
+mod literal;
+mod loki;
+mod merge;
+mod meta;
+mod multiline;
+mod otlp;
+mod preset;
+mod query_error;
+mod source;
+mod syslog;
+mod tail;
+mod timestamp;
+mod validator;
+mod value;
 mod visitor;
 
 use crate::ast::*;
+use crate::decoder::Format;
+use crate::discovery::{Discovery, Glob};
+use crate::field_path::FieldPath;
+use crate::ingest::{Ack, BodyError, Fsync, Listen, Store};
+use crate::merge::{MergeByTime, Order};
+use crate::meta::Sidecars;
+use crate::multiline::Multiline;
+use crate::query_error::QueryError;
+use crate::source::LogFile;
+use crate::tail::{Sink, Tailer};
+use crate::timestamp::Zone;
+use crate::validator::Validator;
 use crate::visitor::{Visitable, Visitor};
 
 struct SearchBuilder<'closures> {
@@ -45,19 +84,38 @@ impl<'closures> SearchBuilder<'closures> {
     }
 }
 
+// search terms run on the raw line, where a quote in a json string is still written as \"
+fn json_escaped(term: &str) -> Option<String> {
+    let encoded = serde_json::to_string(term).unwrap();
+    let escaped = &encoded[1..encoded.len() - 1];
+    if escaped == term {
+        None
+    } else {
+        Some(escaped.to_owned())
+    }
+}
+
+fn contains_term(line: &str, term: &str, escaped: &Option<String>) -> bool {
+    line.contains(term) || escaped.as_ref().map_or(false, |escaped| line.contains(escaped.as_str()))
+}
+
 impl<'ast> Visitor<'ast> for SearchBuilder<'ast> {
     fn visit_search(&mut self, _search: &'ast Search<'ast>) {}
     fn visit_search_term(&mut self, search_term: &'ast SearchTerm<'ast>) {
         match search_term {
             SearchTerm::Include(term) => {
+                let escaped = json_escaped(term);
                 self.search_stage.push(Box::new(move |iter| {
-                    let res: Box<dyn Iterator<Item = String>> = Box::new(iter.filter(move |line| line.contains(*term)));
+                    let escaped = escaped.clone();
+                    let res: Box<dyn Iterator<Item = String>> = Box::new(iter.filter(move |line| contains_term(line, term, &escaped)));
                     res
                 }))
             },
             SearchTerm::Exclude(term) => {
+                let escaped = json_escaped(term);
                 self.search_stage.push(Box::new(move |iter| {
-                    let res: Box<dyn Iterator<Item = String>> = Box::new(iter.filter(move |line| !line.contains(*term)));
+                    let escaped = escaped.clone();
+                    let res: Box<dyn Iterator<Item = String>> = Box::new(iter.filter(move |line| !contains_term(line, term, &escaped)));
                     res
                 }))
             },
@@ -71,21 +129,26 @@ impl<'ast> Visitor<'ast> for SearchBuilder<'ast> {
                 comparison,
                 value,
             } => {
+                let matcher = match comparison {
+                    Comparison::Match => Some(Regex::new(value.as_ref()).expect("regex is checked by the validator")),
+                    _ => None,
+                };
                 self.transform_stage.push(Box::new(move |iter| {
-                    let pointer = "/".to_owned() + &field.replace(r".", "/");
+                    let matcher = matcher.clone();
                     Box::new(iter.filter(move |line| {
+                        let found = field.get(line);
+                        let ordering = || found.map(|f| value::compare_literal(f, value));
                         match comparison {
-                            Comparison::Eq => {
-                                line.pointer(&pointer).and_then(Value::as_str) == Some(value)
-                            }
-                            Comparison::Ne => {
-                                line.pointer(&pointer).and_then(Value::as_str) != Some(value)
-                            }
-                            Comparison::Match => line
-                                .pointer(&pointer)
-                                .and_then(Value::as_str)
-                                .filter(|f| f.contains(value))
-                                .is_some(), // FIXME: regex?
+                            Comparison::Eq => found.map(value::text).as_deref() == Some(value.as_ref()),
+                            Comparison::Ne => found.map(value::text).as_deref() != Some(value.as_ref()),
+                            Comparison::Match => found
+                                .map(value::text)
+                                .filter(|f| matcher.as_ref().unwrap().is_match(f))
+                                .is_some(),
+                            Comparison::Lt => ordering() == Some(Ordering::Less),
+                            Comparison::Le => matches!(ordering(), Some(Ordering::Less) | Some(Ordering::Equal)),
+                            Comparison::Gt => ordering() == Some(Ordering::Greater),
+                            Comparison::Ge => matches!(ordering(), Some(Ordering::Greater) | Some(Ordering::Equal)),
                         }
                     }))}))
             },
@@ -94,20 +157,98 @@ impl<'ast> Visitor<'ast> for SearchBuilder<'ast> {
                 parser,
                 bindings,
             } => {
+                let compiled_parser = Regex::new(parser.as_ref()).expect("regex is checked by the validator");
                 self.transform_stage.push(Box::new(move |iter| {
+                    let compiled_parser = compiled_parser.clone();
+                    // events where the field is missing or doesn't match have nothing to bind, so they're dropped
+                    Box::new(iter.filter_map(move |mut line| {
+                        let captured: Vec<Value> = {
+                            let source = value::text(field.get(&line)?);
+                            let cap = compiled_parser.captures(&source)?;
+                            bindings
+                                .iter()
+                                .enumerate()
+                                .map(|(idx, binding)| {
+                                    let captured = cap.get(idx + 1).map(|m| Value::String(m.as_str().to_owned()));
+                                    match (captured, binding.cast) {
+                                        (Some(captured), Some(to)) => value::cast(&captured, to).unwrap_or(Value::Null),
+                                        (Some(captured), None) => captured,
+                                        (None, _) => Value::Null,
+                                    }
+                                })
+                                .collect()
+                        };
+                        for (binding, value) in bindings.iter().zip(captured) {
+                            binding.field.set(&mut line, value);
+                        }
+                        Some(line)
+                    }))
+                }))
+            },
+            Transform::Cast { field, to } => {
+                self.transform_stage.push(Box::new(move |iter| {
+                    // a missing field stays missing, one that can't be read as the type becomes null
                     Box::new(iter.map(move |mut line| {
-                        let pointer = "/".to_owned() + &(*field).replace(r".", "/");
-                        let compiled_parser = Regex::new(parser).unwrap(); // TODO: error handling. invalid regex?
-                        let cap = compiled_parser
-                            .captures(line.pointer(&pointer).and_then(Value::as_str).unwrap())
-                            .unwrap(); // TODO: so much error handling
-                        let aliases_and_value: Vec<(&&str, String)> = bindings
-                            .iter()
-                            .enumerate()
-                            .map(|(idx, alias)| (alias, cap[idx + 1].to_owned()))
-                            .collect();
-                        for (binding, value) in aliases_and_value {
-                            line[binding] = Value::String(value);
+                        if let Some(found) = field.get(&line) {
+                            let cast = value::cast(found, *to).unwrap_or(Value::Null);
+                            field.set(&mut line, cast);
+                        }
+                        line
+                    }))
+                }))
+            },
+            // times that can't be read leave the target unset rather than dropping the event
+            Transform::ParseTime { field, format, target } => {
+                let target = target.clone().unwrap_or_else(|| FieldPath::parse("_time", 0).unwrap());
+                self.transform_stage.push(Box::new(move |iter| {
+                    let target = target.clone();
+                    Box::new(iter.map(move |mut line| {
+                        let time = field
+                            .get(&line)
+                            .and_then(|found| timestamp::parse(found, format.as_deref(), &Zone::default()));
+                        if let Some(time) = time {
+                            target.set(&mut line, json!(timestamp::canonical(&time)));
+                        }
+                        line
+                    }))
+                }))
+            },
+            Transform::FormatTime { field, format, zone, target } => {
+                let zone = zone
+                    .as_ref()
+                    .map_or_else(Zone::default, |zone| Zone::parse(zone).expect("timezone is checked by the validator"));
+                self.transform_stage.push(Box::new(move |iter| {
+                    Box::new(iter.map(move |mut line| {
+                        let time = field
+                            .get(&line)
+                            .and_then(|found| timestamp::parse(found, None, &Zone::default()));
+                        if let Some(time) = time {
+                            target.as_ref().unwrap_or(field).set(&mut line, json!(zone.format(&time, format)));
+                        }
+                        line
+                    }))
+                }))
+            },
+            Transform::TimeDiff { from, to, target } => {
+                self.transform_stage.push(Box::new(move |iter| {
+                    Box::new(iter.map(move |mut line| {
+                        let read = |field: &FieldPath| field.get(&line).and_then(|found| timestamp::parse(found, None, &Zone::default()));
+                        if let (Some(from), Some(to)) = (read(from), read(to)) {
+                            target.set(&mut line, json!(timestamp::diff_seconds(&from, &to)));
+                        }
+                        line
+                    }))
+                }))
+            },
+            Transform::ConvertTz { field, zone, target } => {
+                let zone = Zone::parse(zone).expect("timezone is checked by the validator");
+                self.transform_stage.push(Box::new(move |iter| {
+                    Box::new(iter.map(move |mut line| {
+                        let time = field
+                            .get(&line)
+                            .and_then(|found| timestamp::parse(found, None, &Zone::default()));
+                        if let Some(time) = time {
+                            target.as_ref().unwrap_or(field).set(&mut line, json!(zone.to_rfc3339(&time)));
                         }
                         line
                     }))
@@ -120,21 +261,23 @@ impl<'ast> Visitor<'ast> for SearchBuilder<'ast> {
         match aggregation {
             Aggregation::Count(fields) => {
                 self.transform_stage.push(Box::new(move |iter| {
-                    let new_source = HashMap::new();
+                    // keyed by the json of each value so 200 and "200" stay apart; missing counts as null
+                    let new_source: HashMap<Vec<String>, (Vec<Value>, usize)> = HashMap::new();
                     Box::new(iter.fold(new_source, |mut acc, json| {
-                        let identity: Vec<String> = fields
+                        let values: Vec<Value> = fields
                             .iter()
-                            .map(|k| json[k].as_str().unwrap().to_owned())
+                            .map(|k| k.get(&json).cloned().unwrap_or(Value::Null))
                             .collect();
-                        let counter = acc.entry(identity).or_insert(0);
-                        *counter += 1;
+                        let identity = values.iter().map(Value::to_string).collect();
+                        let counter = acc.entry(identity).or_insert((values, 0));
+                        counter.1 += 1;
                         acc
                     })
                     .into_iter()
-                    .map(move |(k, v)| {
+                    .map(move |(_, (values, v))| {
                         let mut json = json!({});
-                        for (i, key) in fields.iter().enumerate() {
-                            json[key] = json!(k[i]);
+                        for (key, value) in fields.iter().zip(values) {
+                            key.set(&mut json, value);
                         }
                         json["_count"] = json!(v);
                         json
@@ -144,73 +287,389 @@ impl<'ast> Visitor<'ast> for SearchBuilder<'ast> {
             // no other supported for now
         }
     }
-    fn visit_sort(&mut self, sort: &'ast Sort<'ast>) {}
+    fn visit_sort(&mut self, sort: &'ast Sort<'ast>) {
+        let (fields, descending) = match sort {
+            Sort::Asc(fields) => (fields, false),
+            Sort::Desc(fields) => (fields, true),
+        };
+        self.transform_stage.push(Box::new(move |iter| {
+            let mut sorted: Vec<Value> = iter.collect();
+            sorted.sort_by(|a, b| {
+                let ordering = fields
+                    .iter()
+                    .map(|field| value::compare(field.get(a), field.get(b)))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal);
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+            Box::new(sorted.into_iter())
+        }))
+    }
 }
 
 
-#[get("/")]
+#[get("/", rank = 2)]
 fn index() -> NamedFile {
     NamedFile::open("assets/index.html").unwrap()
 }
 
-#[get("/search?<q>")]
-fn search(q: String, config: State<StillConfig>) -> Json<Vec<Value>> {
-    let search: Search = *search::SearchParser::new().parse(&q).unwrap();
+fn query_errors(errors: Vec<QueryError>) -> BadRequest<Json<Value>> {
+    let errors: Vec<Value> = errors.iter().map(QueryError::to_json).collect();
+    BadRequest(Some(Json(json!({ "errors": errors }))))
+}
 
-    let entries = read_dir(config.logs_dir.as_ref())
-        .unwrap()
-        .map(|res| res.unwrap())
-        .filter_map(|e| {
-            if e.path().extension() == Some(&OsString::from("log")) {
-                Some(e.path())
-            } else {
-                None
-            }
-        })
-        .map(|p| BufReader::new(File::open(p).unwrap()).lines())
-        .flatten();
-        
-    let lines: Box<dyn Iterator<Item = String>> =
-        Box::new(entries.map(|l| l.unwrap()));
+fn invalid_parameter(kind: &str, message: String) -> BadRequest<Json<Value>> {
+    BadRequest(Some(Json(json!({ "errors": [{ "kind": kind, "message": message }] }))))
+}
 
+// every file gets its own search stage so that `order` can interleave them by time
+// before the transforms see a single stream. everything is pulled lazily, so with a `limit`
+// (and newest first, where files are read from their end) only as much is read as it takes
+fn execute<'a>(search: &'a Search<'a>, files: &'a [LogFile], order: Option<Order>, limit: Option<usize>) -> Vec<Value> {
     let mut search_builder = SearchBuilder::new();
     search.accept(&mut search_builder);
-    let filtered: Box<dyn Iterator<Item = String>> = search_builder
-        .search_stage
-        .iter_mut()
-        .fold(lines, |iter, iter_transformer| {
-            Box::new(iter_transformer(iter))
-        });
-    let json_parsed: Box<dyn Iterator<Item = Value>> =
-        Box::new(filtered.filter_map(|line| serde_json::from_str(&line).ok()));
+    let search_stage = &mut search_builder.search_stage;
+    let streams: Vec<Box<dyn Iterator<Item = Value> + 'a>> = files
+        .iter()
+        .map(|file| {
+            let backwards = order == Some(Order::Newest);
+            let lines: Box<dyn Iterator<Item = String> + 'a> = if backwards {
+                source::read_lines_backwards(&file.path)
+            } else {
+                source::read_lines(&file.path)
+            };
+            let lines = decoder::decode(lines, file.options.format, backwards);
+            let lines = match &file.options.multiline {
+                Some(rule) => multiline::assemble(lines, rule, backwards),
+                None => lines,
+            };
+            let filtered = search_stage
+                .iter_mut()
+                .fold(lines, |iter, iter_transformer| iter_transformer(iter));
+            let json_parsed: Box<dyn Iterator<Item = Value> + 'a> =
+                Box::new(filtered.filter_map(move |line| source::to_event(&line, file)));
+            json_parsed
+        })
+        .collect();
+    let events: Box<dyn Iterator<Item = Value> + 'a> = match order {
+        Some(order) => Box::new(MergeByTime::new(streams, order)),
+        None => Box::new(streams.into_iter().flatten()),
+    };
     let transformed = search_builder
         .transform_stage
         .iter_mut()
-        .fold(json_parsed, |iter, iter_transformer| {
-            Box::new(iter_transformer(iter))
-        });
-    Json(transformed.collect::<Vec<Value>>())
+        .fold(events, |iter, iter_transformer| iter_transformer(iter));
+    transformed.take(limit.unwrap_or(usize::MAX)).collect()
+}
+
+#[get("/search?<q>&<order>&<limit>")]
+fn search(q: String, order: Option<String>, limit: Option<String>, config: State<StillConfig>) -> Result<Json<Vec<Value>>, BadRequest<Json<Value>>> {
+    let search: Search = *search::SearchParser::new()
+        .parse(&q)
+        .map_err(|e| query_errors(vec![QueryError::from_parse_error(&q, e)]))?;
+    let mut validator = Validator::new(&q);
+    search.accept(&mut validator);
+    if !validator.errors.is_empty() {
+        return Err(query_errors(validator.errors));
+    }
+    let order = match order.as_deref() {
+        None | Some("") | Some("file") => None,
+        Some(name) => Some(Order::from_name(name).ok_or_else(|| {
+            invalid_parameter("invalid_order", format!("unknown order `{}`, expected one of file, oldest, newest", name))
+        })?),
+    };
+
+    let limit = match limit.as_deref() {
+        None | Some("") => None,
+        Some(text) => Some(text.parse::<usize>().map_err(|_| {
+            invalid_parameter("invalid_limit", format!("limit `{}` is not a number of results", text))
+        })?),
+    };
+
+    let mut files = source::log_files(&config.logs_dir, &config.discovery);
+    for file in files.iter_mut() {
+        if config.json_only.contains(&file.name) {
+            file.options.raw_lines = false;
+        }
+        if let Some(format) = config.formats.get(&file.name) {
+            file.options.format = *format;
+        }
+        if let Some(rule) = config.multiline.get(&file.name) {
+            file.options.multiline = Some(rule.clone());
+        }
+        // a file's own sidecar has the last word
+        config.sidecars.apply(file);
+    }
+    Ok(Json(execute(&search, &files, order, limit)))
+}
+
+fn batch_error(status: Status, message: String) -> Custom<Json<Value>> {
+    Custom(status, Json(json!({ "errors": [{ "kind": "invalid_batch", "message": message }] })))
+}
+
+// a full buffer asks the client to come back later
+fn store_error(e: std::io::Error) -> Custom<Json<Value>> {
+    if ingest::buffer_full(&e) {
+        batch_error(Status::TooManyRequests, e.to_string())
+    } else {
+        batch_error(Status::InternalServerError, format!("can't store the batch: {}", e))
+    }
+}
+
+fn body_error(e: BodyError) -> Custom<Json<Value>> {
+    match e {
+        BodyError::TooLarge(limit) => batch_error(Status::PayloadTooLarge, format!("batches are limited to {} bytes", limit)),
+        BodyError::Invalid(message) => batch_error(Status::BadRequest, message),
+    }
+}
+
+fn read_batch(body: Data, limit: u64) -> Result<Vec<u8>, Custom<Json<Value>>> {
+    let mut bytes = vec![];
+    body.open()
+        .take(limit + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| batch_error(Status::BadRequest, e.to_string()))?;
+    if bytes.len() as u64 > limit {
+        return Err(body_error(BodyError::TooLarge(limit)));
+    }
+    Ok(bytes)
+}
+
+fn store_batch(store: &Store, events: Vec<Value>, rejected: ingest::Rejected) -> Result<Json<Value>, Custom<Json<Value>>> {
+    if events.is_empty() && !rejected.is_empty() {
+        let ack = Ack { batch: 0, accepted: 0, rejected };
+        return Err(Custom(Status::BadRequest, Json(ack.to_json())));
+    }
+    let ack = store
+        .append(&events, rejected)
+        .map_err(store_error)?;
+    Ok(Json(ack.to_json()))
+}
+
+#[post("/ingest", data = "<body>")]
+fn ingest(body: Data, config: State<StillConfig>, store: State<Arc<Store>>) -> Result<Json<Value>, Custom<Json<Value>>> {
+    let bytes = read_batch(body, config.ingest_limit)?;
+    let (events, rejected) = ingest::decode_batch(&bytes, config.ingest_limit).map_err(body_error)?;
+    store_batch(&store, events, rejected)
+}
+
+// promtail and grafana agent send snappy protobuf, other clients json
+#[post("/loki/api/v1/push", data = "<body>")]
+fn loki_push(
+    body: Data,
+    content_type: Option<&ContentType>,
+    config: State<StillConfig>,
+    store: State<Arc<Store>>,
+) -> Result<Json<Value>, Custom<Json<Value>>> {
+    let bytes = read_batch(body, config.ingest_limit)?;
+    let protobuf = content_type.map_or(false, |t| t.top() == "application" && t.sub() == "x-protobuf");
+    let (events, rejected) = loki::decode_push(&bytes, protobuf, config.ingest_limit).map_err(body_error)?;
+    store_batch(&store, events, rejected)
+}
+
+// opentelemetry sdks and collectors export protobuf, or json when asked to
+#[post("/v1/logs", data = "<body>")]
+fn otlp_logs(
+    body: Data,
+    content_type: Option<&ContentType>,
+    config: State<StillConfig>,
+    store: State<Arc<Store>>,
+) -> Result<Json<Value>, Custom<Json<Value>>> {
+    let bytes = read_batch(body, config.ingest_limit)?;
+    let protobuf = content_type.map_or(false, |t| t.top() == "application" && t.sub() == "x-protobuf");
+    let (events, rejected) = otlp::decode_logs(&bytes, protobuf, config.ingest_limit).map_err(body_error)?;
+    store_batch(&store, events, rejected)
+}
+
+// what elasticsearch answers with: json, and the header its newer clients check they're talking to it
+struct Elastic(Value);
+
+impl<'r> Responder<'r> for Elastic {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(Json(self.0).respond_to(request)?)
+            .raw_header("X-Elastic-Product", "Elasticsearch")
+            .ok()
+    }
+}
+
+// beats and vector ask for the version before they send anything; browsers still get the ui
+#[get("/", format = "json")]
+fn elastic_root() -> Elastic {
+    Elastic(elasticsearch::root())
+}
+
+// the documents of each index go to its own dir under `ingest/indices`, all in one batch
+fn bulk(body: Data, index: Option<&str>, config: &StillConfig, store: &Store) -> Result<Elastic, Custom<Json<Value>>> {
+    let started = Instant::now();
+    let bytes = read_batch(body, config.ingest_limit)?;
+    let text = ingest::body_text(&bytes, config.ingest_limit).map_err(body_error)?;
+    let items = elasticsearch::decode_bulk(&text, index).map_err(|e| batch_error(Status::BadRequest, e))?;
+    let mut indices: HashMap<String, Vec<Value>> = HashMap::new();
+    for item in &items {
+        if let Ok(document) = &item.outcome {
+            let dir = format!("indices/{}", item.index);
+            indices.entry(dir).or_insert_with(Vec::new).push(document.clone());
+        }
+    }
+    let dirs: Vec<(&str, &[Value])> = indices.iter().map(|(dir, documents)| (dir.as_str(), documents.as_slice())).collect();
+    store.append_each(&dirs, vec![]).map_err(store_error)?;
+    Ok(Elastic(elasticsearch::response(&items, started.elapsed().as_millis() as i64)))
+}
+
+#[post("/_bulk", data = "<body>")]
+fn bulk_any(body: Data, config: State<StillConfig>, store: State<Arc<Store>>) -> Result<Elastic, Custom<Json<Value>>> {
+    bulk(body, None, &config, &store)
+}
+
+#[post("/<index>/_bulk", data = "<body>")]
+fn bulk_index(index: String, body: Data, config: State<StillConfig>, store: State<Arc<Store>>) -> Result<Elastic, Custom<Json<Value>>> {
+    bulk(body, Some(&index), &config, &store)
 }
 
 struct StillConfig {
     logs_dir: Box<Path>,
+    // sources whose non-json lines are dropped rather than kept as `_raw`
+    json_only: Vec<String>,
+    // multiline rule of each source that has one, as in `Multiline::parse`
+    multiline: HashMap<String, Multiline>,
+    // the format of each source that isn't plain lines: cri or docker
+    formats: HashMap<String, Format>,
+    discovery: Discovery,
+    // the largest /ingest body accepted, in bytes
+    ingest_limit: u64,
+    sidecars: Sidecars,
 }
 
 fn main() {
     rocket::ignite()
         .attach(AdHoc::on_attach("Load Config", |rocket| {
-            let logs_dir = Box::from(Path::new(rocket.config().get_str("logs_dir").unwrap_or(".")));
-            Ok(rocket.manage(StillConfig{ logs_dir }))
+            let logs_dir: Box<Path> = Box::from(Path::new(rocket.config().get_str("logs_dir").unwrap_or(".")));
+            let json_only = rocket
+                .config()
+                .get_slice("json_only")
+                .map(|sources| sources.iter().filter_map(|s| s.as_str()).map(String::from).collect())
+                .unwrap_or_default();
+            let mut multiline = HashMap::new();
+            for (source, spec) in rocket.config().get_table("multiline").into_iter().flatten() {
+                match spec.as_str().ok_or_else(|| "not a string".to_owned()).and_then(Multiline::parse) {
+                    Ok(rule) => {
+                        multiline.insert(source.clone(), rule);
+                    }
+                    Err(e) => {
+                        eprintln!("multiline rule for {}: {}", source, e);
+                        return Err(rocket);
+                    }
+                }
+            }
+            let mut formats = HashMap::new();
+            for (source, name) in rocket.config().get_table("format").into_iter().flatten() {
+                match name.as_str().and_then(Format::from_name) {
+                    Some(format) => {
+                        formats.insert(source.clone(), format);
+                    }
+                    None => {
+                        eprintln!("format of {}: expected one of lines, cri, docker", source);
+                        return Err(rocket);
+                    }
+                }
+            }
+            let globs = |key: &str| -> Result<Vec<Glob>, String> {
+                rocket
+                    .config()
+                    .get_slice(key)
+                    .map(|globs| globs.iter().map(|g| g.as_str().ok_or_else(|| "not a string".to_owned()).and_then(Glob::parse)).collect())
+                    .unwrap_or_else(|_| Ok(vec![]))
+            };
+            let discovery = match (globs("include"), globs("exclude")) {
+                (Ok(include), Ok(exclude)) => Discovery {
+                    include,
+                    exclude,
+                    follow_symlinks: rocket.config().get_bool("follow_symlinks").unwrap_or(true),
+                },
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("include/exclude: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let ingest_limit = rocket.config().get_int("ingest_limit").map(|limit| limit as u64).unwrap_or(16 * 1024 * 1024);
+            let fsync = match Fsync::parse(rocket.config().get_str("ingest_fsync").unwrap_or("batch")) {
+                Ok(fsync) => fsync,
+                Err(e) => {
+                    eprintln!("ingest_fsync: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let ingest_buffer = rocket.config().get_int("ingest_buffer").map(|limit| limit as u64).unwrap_or(256 * 1024 * 1024);
+            let store = match Store::open(logs_dir.join("ingest"), fsync, ingest_buffer) {
+                Ok(store) => store,
+                Err(e) => {
+                    eprintln!("ingest wal: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let listeners: [(&str, Listen); 5] = [
+                ("forward_port", forward::listen),
+                ("gelf_tcp_port", gelf::listen_tcp),
+                ("gelf_udp_port", gelf::listen_udp),
+                ("syslog_tcp_port", syslog::listen_tcp),
+                ("syslog_udp_port", syslog::listen_udp),
+            ];
+            for (key, listen) in listeners.iter() {
+                if let Ok(port) = rocket.config().get_int(key) {
+                    let address = format!("{}:{}", rocket.config().address, port);
+                    if let Err(e) = listen(&address, store.clone()) {
+                        eprintln!("{} {}: {}", key, address, e);
+                        return Err(rocket);
+                    }
+                }
+            }
+            // agent mode: tail files outside the logs dir into the store, or to another still
+            let tail: Vec<String> = rocket
+                .config()
+                .get_slice("tail")
+                .map(|patterns| patterns.iter().filter_map(|p| p.as_str()).map(String::from).collect())
+                .unwrap_or_default();
+            if !tail.is_empty() {
+                let checkpoint = rocket
+                    .config()
+                    .get_str("tail_checkpoint")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| logs_dir.join("tail.checkpoint"));
+                let sink = match rocket.config().get_str("tail_forward") {
+                    Ok(url) => Sink::Forward(url.to_owned()),
+                    Err(_) => Sink::Store(store.clone()),
+                };
+                let started = Tailer::new(&tail, checkpoint, sink).and_then(|tailer| tail::run(tailer).map_err(|e| e.to_string()));
+                if let Err(e) = started {
+                    eprintln!("tail: {}", e);
+                    return Err(rocket);
+                }
+            }
+            Ok(rocket
+                .manage(StillConfig{ logs_dir, json_only, multiline, formats, discovery, ingest_limit, sidecars: Sidecars::default() })
+                .manage(store))
         }))
-        .mount("/", routes![index, search])
+        .mount("/", routes![index, elastic_root, search, ingest, loki_push, otlp_logs, bulk_any, bulk_index])
         .launch();
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::SearchBuilder;
+    use super::{execute, SearchBuilder};
     use crate::ast::*;
+    use crate::decoder::Format;
+    use crate::discovery::Discovery;
+    use crate::field_path::FieldPath;
+    use crate::merge::Order;
+    use crate::meta;
+    use crate::multiline::Multiline;
+    use crate::source;
+    use crate::timestamp;
     use crate::visitor::{Visitable, Visitor};
     use regex::Regex;
     use serde_json::json;
@@ -221,6 +680,7 @@ mod tests {
     use std::fs::File;
     use std::io::prelude::*;
     use std::io::BufReader;
+    use std::path::Path;
     lalrpop_mod!(pub search);
 
     #[test]
@@ -345,6 +805,14 @@ mod tests {
             .is_ok());
     }
 
+    fn field(path: &str) -> FieldPath {
+        FieldPath::parse(path, 0).unwrap()
+    }
+
+    fn binding(path: &str) -> Binding {
+        Binding { field: field(path), cast: None }
+    }
+
     #[test]
     fn lalrpop_ast_sketch() {
         let (search_terms, transforms, sort): Search = *search::SearchParser::new()
@@ -361,40 +829,82 @@ mod tests {
             .unwrap();
         assert_eq!(
             vec![
-                SearchTerm::Include("ingress"),
-                SearchTerm::Include("protocol.kitchen"),
-                SearchTerm::Exclude("feedme"),
-                SearchTerm::Exclude(r#"GET /assets"#), // FIXME: needs unquoting but might be better done by the ast analyser rathen than in-parsing (replace changes types from &'input str to String with temp ownership)
+                SearchTerm::Include("ingress".into()),
+                SearchTerm::Include("protocol.kitchen".into()),
+                SearchTerm::Exclude("feedme".into()),
+                SearchTerm::Exclude(r#"GET /assets"#.into()),
             ],
             search_terms
         );
         assert_eq!(
             vec![
                 Transform::Filter {
-                    field: "stream",
+                    field: field("stream"),
                     comparison: Comparison::Ne,
-                    value: r#"stderr"#
+                    value: r#"stderr"#.into()
                 },
                 Transform::Filter {
-                    field: "kubernetes.namespace_name",
+                    field: field("kubernetes.namespace_name"),
                     comparison: Comparison::Eq,
-                    value: r#"protocol-kitchen"#
+                    value: r#"protocol-kitchen"#.into()
                 },
                 Transform::Parse {
-                    field: "log",
-                    parser: r#""([^ ]+) ([^ ]+) HTTP/1.1" ([\d]{3})"#,
-                    bindings: vec!["verb", "path", "response_code"]
+                    field: field("log"),
+                    parser: r#""([^ ]+) ([^ ]+) HTTP/1.1" ([\d]{3})"#.into(),
+                    bindings: vec![binding("verb"), binding("path"), binding("response_code")]
                 },
                 Transform::Filter {
-                    field: "response_code",
+                    field: field("response_code"),
                     comparison: Comparison::Eq,
-                    value: r#"200"#
+                    value: r#"200"#.into()
                 },
-                Transform::Aggregate(Aggregation::Count(vec!["verb", "path"])),
+                Transform::Aggregate(Aggregation::Count(vec![field("verb"), field("path")])),
+            ],
+            transforms
+        );
+        assert_eq!(Some(Sort::Desc(vec![field("_count")])), sort);
+    }
+
+    #[test]
+    fn escaped_quote_matches_json_encoded_line() {
+        let (search_terms, _, _): Search = *search::SearchParser::new()
+            .parse(r#"!"\"GET /assets""#)
+            .unwrap();
+        assert_eq!(vec![SearchTerm::Exclude(r#""GET /assets"#.into())], search_terms);
+
+        let line = r#"{"log":"10.244.0.70 - - [30/May/2020:09:51:27 +0000] \"GET /assets/app.js HTTP/1.1\" 200"}"#;
+        let term = r#""GET /assets"#;
+        assert!(super::contains_term(line, term, &super::json_escaped(term)));
+        assert!(!super::contains_term(line, r#""GET /index"#, &super::json_escaped(r#""GET /index"#)));
+    }
+
+    #[test]
+    fn lalrpop_field_paths() {
+        let (_, transforms, _): Search = *search::SearchParser::new()
+            .parse(r#"* | where kubernetes.annotations."clusterlint.digitalocean.com/disabled-checks" match "root" | count by containers[0].name"#)
+            .unwrap();
+        assert_eq!(
+            vec![
+                Transform::Filter {
+                    field: field(r#"kubernetes.annotations."clusterlint.digitalocean.com/disabled-checks""#),
+                    comparison: Comparison::Match,
+                    value: "root".into()
+                },
+                Transform::Aggregate(Aggregation::Count(vec![field("containers[0].name")])),
+            ],
+            transforms
+        );
+
+        let (_, transforms, _): Search = *search::SearchParser::new()
+            .parse(r#"* | where "@timestamp" = "x" | count by "kubernetes.io".labels[0], "a b""#)
+            .unwrap();
+        assert_eq!(
+            vec![
+                Transform::Filter { field: field(r#""@timestamp""#), comparison: Comparison::Eq, value: "x".into() },
+                Transform::Aggregate(Aggregation::Count(vec![field(r#""kubernetes.io".labels[0]"#), field(r#""a b""#)])),
             ],
             transforms
         );
-        assert_eq!(Some(Sort::Desc(vec!["_count"])), sort);
     }
 
     struct TestVisitor {
@@ -404,6 +914,9 @@ mod tests {
         filters_equal: usize,
         filters_not_equal: usize,
         filters_match: usize,
+        filters_ordering: usize,
+        casts: usize,
+        time_functions: usize,
         parses: usize,
         // captured_fields: usize,
         bound_fields: usize,
@@ -432,7 +945,13 @@ mod tests {
                     Comparison::Eq => self.filters_equal += 1,
                     Comparison::Ne => self.filters_not_equal += 1,
                     Comparison::Match => self.filters_match += 1,
+                    _ => self.filters_ordering += 1,
                 },
+                Transform::Cast { field: _, to: _ } => self.casts += 1,
+                Transform::ParseTime { .. }
+                | Transform::FormatTime { .. }
+                | Transform::TimeDiff { .. }
+                | Transform::ConvertTz { .. } => self.time_functions += 1,
                 Transform::Parse {
                     field: _,
                     parser: _,
@@ -477,6 +996,9 @@ mod tests {
             filters_equal: 0,
             filters_not_equal: 0,
             filters_match: 0,
+            filters_ordering: 0,
+            casts: 0,
+            time_functions: 0,
             parses: 0,
             bound_fields: 0,
             aggregations: 0,
@@ -490,6 +1012,9 @@ mod tests {
         assert_eq!(2, test_visitor.filters_equal);
         assert_eq!(1, test_visitor.filters_not_equal);
         assert_eq!(0, test_visitor.filters_match);
+        assert_eq!(0, test_visitor.filters_ordering);
+        assert_eq!(0, test_visitor.casts);
+        assert_eq!(0, test_visitor.time_functions);
         assert_eq!(1, test_visitor.parses);
         assert_eq!(3, test_visitor.bound_fields);
         assert_eq!(1, test_visitor.aggregations);
@@ -636,6 +1161,198 @@ mod tests {
         assert_eq!(4, got.len());
     }
     
+    fn run_on_fixtures(query: &str) -> Vec<Value> {
+        run_on_fixtures_in_order(query, None)
+    }
+
+    fn run_on_fixtures_in_order(query: &str, order: Option<Order>) -> Vec<Value> {
+        let search: Search = *search::SearchParser::new().parse(query).unwrap();
+        let files = source::log_files(Path::new("fixtures"), &Discovery::default());
+        execute(&search, &files, order, None)
+    }
+
+    #[test]
+    fn merges_files_into_one_timeline() {
+        let times = |order| -> Vec<_> {
+            run_on_fixtures_in_order("*", Some(order))
+                .iter()
+                .map(|event| timestamp::event_time(event).unwrap())
+                .collect()
+        };
+        let oldest = times(Order::Oldest);
+        assert_eq!(165, oldest.len());
+        assert!(oldest.windows(2).all(|pair| pair[0] <= pair[1]));
+        let mut newest = times(Order::Newest);
+        newest.reverse();
+        assert_eq!(oldest, newest);
+    }
+
+    #[test]
+    fn latest_results_with_a_limit() {
+        let search: Search = *search::SearchParser::new().parse("GET").unwrap();
+        let files = source::log_files(Path::new("fixtures"), &Discovery::default());
+        let got: Vec<Value> = execute(&search, &files, Some(Order::Newest), Some(2))
+            .iter()
+            .map(|event| event["time"].clone())
+            .collect();
+        assert_eq!(vec![json!("2020-05-30T09:51:41.50014243Z"), json!("2020-05-30T09:51:41.464814155Z")], got);
+    }
+
+    #[test]
+    fn plain_text_lines_are_raw_events() {
+        let dir = std::env::temp_dir().join(format!("still-raw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.log"), "INFO started\nERROR disk full\n{\"level\":\"ERROR\"}\n").unwrap();
+        let search: Search = *search::SearchParser::new().parse(r#"ERROR | parse _raw with 'ERROR (.+)' as reason"#).unwrap();
+        let mut files = source::log_files(&dir, &Discovery::default());
+        assert_eq!(
+            vec![json!({"_raw": "ERROR disk full", "_source": "app.log", "reason": "disk full"})],
+            execute(&search, &files, None, None)
+        );
+        files[0].options.raw_lines = false;
+        let search: Search = *search::SearchParser::new().parse("ERROR").unwrap();
+        assert_eq!(vec![json!({"level": "ERROR"})], execute(&search, &files, None, None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn multiline_events_match_as_a_whole() {
+        let dir = std::env::temp_dir().join(format!("still-multiline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("app.log"),
+            "INFO starting\nERROR request failed\n  at Oven.bake\n  at Kitchen.serve\nINFO done\n",
+        )
+        .unwrap();
+        let mut files = source::log_files(&dir, &Discovery::default());
+        files[0].options.multiline = Some(Multiline::parse("indented").unwrap());
+        let search: Search = *search::SearchParser::new().parse("Kitchen").unwrap();
+        let raw = json!("ERROR request failed\n  at Oven.bake\n  at Kitchen.serve");
+        assert_eq!(raw, execute(&search, &files, None, None)[0]["_raw"]);
+        assert_eq!(raw, execute(&search, &files, Some(Order::Newest), None)[0]["_raw"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cri_logs_read_like_fluentd_ones() {
+        let dir = std::env::temp_dir().join(format!("still-cri-{}", std::process::id()));
+        let pod = dir.join("pods/netronner_netronner-ui-656c94c66b-bwvx2_uid/netronner-ui");
+        std::fs::create_dir_all(&pod).unwrap();
+        std::fs::write(
+            pod.join("0.log"),
+            "2020-05-30T09:51:27.5Z stdout P GET /icons/\n2020-05-30T09:51:27.6Z stdout F seventh_son.png\n",
+        )
+        .unwrap();
+        let mut files = source::log_files(&dir, &Discovery::default());
+        files[0].options.format = Format::Cri;
+        let search: Search = *search::SearchParser::new()
+            .parse("* | where kubernetes.namespace_name = netronner | parse log with 'GET (\\S+)' as path")
+            .unwrap();
+        assert_eq!(
+            vec![json!({
+                "log": "GET /icons/seventh_son.png\n",
+                "stream": "stdout",
+                "time": "2020-05-30T09:51:27.5Z",
+                "path": "/icons/seventh_son.png",
+                "kubernetes": {
+                    "pod_name": "netronner-ui-656c94c66b-bwvx2",
+                    "namespace_name": "netronner",
+                    "container_name": "netronner-ui",
+                    "pod_id": "uid",
+                },
+            })],
+            execute(&search, &files, None, None)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecars_say_how_to_read_each_file() {
+        let dir = std::env::temp_dir().join(format!("still-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("oven.log"), "ts=\"2020-05-30 11:51:27\" level=error msg=\"too hot\"\n").unwrap();
+        std::fs::write(
+            dir.join("oven.log.meta"),
+            "type = \"text\"\nparsers = [\"logfmt\"]\ntimezone = \"Europe/Rome\"\n[fields]\nteam = \"kitchen\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notlog.log.meta"), "garbage").unwrap();
+        let mut files = source::log_files(&dir, &Discovery::default());
+        for file in files.iter_mut() {
+            meta::apply(file).unwrap();
+        }
+        let search: Search = *search::SearchParser::new().parse("* | where level = error").unwrap();
+        assert_eq!(
+            vec![json!({
+                "_raw": "ts=\"2020-05-30 11:51:27\" level=error msg=\"too hot\"",
+                "_source": "oven.log",
+                "ts": "2020-05-30 11:51:27",
+                "level": "error",
+                "msg": "too hot",
+                "team": "kitchen",
+                "_time": "2020-05-30T09:51:27.000000000Z",
+            })],
+            execute(&search, &files, None, None)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn count_by_nested_field_sorted() {
+        let got = run_on_fixtures("* | count by kubernetes.namespace_name | sort by _count");
+        assert_eq!(
+            vec![
+                json!({"kubernetes": {"namespace_name": "ingress-nginx"}, "_count": 81}),
+                json!({"kubernetes": {"namespace_name": "protocol-kitchen"}, "_count": 38}),
+                json!({"kubernetes": {"namespace_name": "netronner"}, "_count": 36}),
+                json!({"kubernetes": {"namespace_name": "feedme-protocol-kitchen"}, "_count": 6}),
+                json!({"kubernetes": {"namespace_name": "kube-system"}, "_count": 4}),
+            ],
+            got
+        );
+    }
+
+    #[test]
+    fn parse_drops_events_it_cannot_bind() {
+        let got = run_on_fixtures(r#"* | parse log with '"(GET|POST) ([^ ]+)' as request.verb, request.path | count by request.verb"#);
+        assert_eq!(vec![json!({"request": {"verb": "GET"}, "_count": 160})], got);
+    }
+
+    #[test]
+    fn typed_bindings_compare_and_sort_as_numbers() {
+        let got = run_on_fixtures(
+            r#"protocol.kitchen
+            | parse log with '"([A-Z]+) ([^ ]+) HTTP/1.1" (\d{3}) (\d+)' as verb, path, status:int, bytes:int
+            | where status >= 300
+            | cast bytes as float
+            | count by status, bytes
+            | sort by status, bytes"#,
+        );
+        assert!(!got.is_empty());
+        assert!(got.iter().all(|json| json["status"].as_i64().unwrap() >= 300 && json["bytes"].is_f64()));
+        assert!(got.windows(2).all(|pair| pair[0]["status"].as_i64() >= pair[1]["status"].as_i64()));
+    }
+
+    #[test]
+    fn time_functions_normalise_every_fixture_format() {
+        let got = run_on_fixtures(
+            r#"netronner
+            | parse_time(time) as fluentd
+            | parse_time(log, "nginx")
+            | time_diff(fluentd, _time) as lag
+            | format_time(_time, "%H:%M", "Europe/London") as london
+            | convert_tz(_time, "+02:00") as cest
+            | count by london, cest
+            | sort by cest"#,
+        );
+        assert_eq!(
+            vec![json!({"london": "10:51", "cest": "2020-05-30T11:51:27.000000000+02:00", "_count": 57})],
+            got[..1].to_vec()
+        );
+        let lags = run_on_fixtures(r#"netronner | parse_time(time) as fluentd | parse_time(log, "nginx") | time_diff(fluentd, _time) as lag | where lag < 1"#);
+        assert!(lags.iter().all(|json| json["lag"].as_f64().unwrap() >= 0.0));
+    }
+
     #[test]
     fn empty_search_yields_everything() {
         let search: Search = *search::SearchParser::new()
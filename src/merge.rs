@@ -0,0 +1,171 @@
+use crate::timestamp;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Oldest,
+    Newest,
+}
+
+impl Order {
+    pub fn from_name(name: &str) -> Option<Order> {
+        match name {
+            "oldest" => Some(Order::Oldest),
+            "newest" => Some(Order::Newest),
+            _ => None,
+        }
+    }
+}
+
+// how many events of one stream are held back to put its stragglers in place: collectors flush
+// several sources into one file, so a file is only ordered by time give or take a few lines
+const REORDER_WINDOW: usize = 64;
+
+// interleaves mostly time-ordered streams (one per file, each in `order`) into a single timeline,
+// holding at most a window of events per stream. an event without a time of its own takes the time
+// of the one before it in the same stream, so continuation lines stay next to their neighbours; one
+// with no time before it at all comes out first
+pub struct MergeByTime<'a> {
+    streams: Vec<Stream<'a>>,
+    heads: BinaryHeap<Head>,
+    order: Order,
+}
+
+struct Stream<'a> {
+    events: Box<dyn Iterator<Item = Value> + 'a>,
+    window: BinaryHeap<Head>,
+    last_time: Option<DateTime<Utc>>,
+    read: usize,
+}
+
+struct Head {
+    rank: (i64, u32),
+    stream: usize,
+    seq: usize,
+    event: Value,
+}
+
+// BinaryHeap pops the greatest: the best rank, then the first stream and the first read on ties
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        self.rank
+            .cmp(&other.rank)
+            .then_with(|| other.stream.cmp(&self.stream))
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl<'a> MergeByTime<'a> {
+    pub fn new(streams: Vec<Box<dyn Iterator<Item = Value> + 'a>>, order: Order) -> MergeByTime<'a> {
+        let mut merge = MergeByTime {
+            streams: streams
+                .into_iter()
+                .map(|events| Stream {
+                    events,
+                    window: BinaryHeap::new(),
+                    last_time: None,
+                    read: 0,
+                })
+                .collect(),
+            heads: BinaryHeap::new(),
+            order,
+        };
+        for stream in 0..merge.streams.len() {
+            merge.advance(stream);
+        }
+        merge
+    }
+
+    // tops up the stream's window and offers its best event to the merge
+    fn advance(&mut self, index: usize) {
+        let order = self.order;
+        let stream = &mut self.streams[index];
+        while stream.window.len() < REORDER_WINDOW {
+            let event = match stream.events.next() {
+                Some(event) => event,
+                None => break,
+            };
+            if let Some(time) = timestamp::event_time(&event) {
+                stream.last_time = Some(time);
+            }
+            let rank = match (stream.last_time, order) {
+                (None, _) => (i64::MAX, u32::MAX),
+                (Some(time), Order::Newest) => (time.timestamp(), time.timestamp_subsec_nanos()),
+                (Some(time), Order::Oldest) => (-time.timestamp(), u32::MAX - time.timestamp_subsec_nanos()),
+            };
+            stream.window.push(Head { rank, stream: index, seq: stream.read, event });
+            stream.read += 1;
+        }
+        if let Some(head) = stream.window.pop() {
+            self.heads.push(head);
+        }
+    }
+}
+
+impl<'a> Iterator for MergeByTime<'a> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let Head { stream, event, .. } = self.heads.pop()?;
+        self.advance(stream);
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MergeByTime, Order};
+    use serde_json::{json, Value};
+
+    fn stream(events: Vec<Value>) -> Box<dyn Iterator<Item = Value>> {
+        Box::new(events.into_iter())
+    }
+
+    #[test]
+    fn interleaves_streams_oldest_first() {
+        let a = stream(vec![json!({"time": "2020-05-30T00:00:01Z", "n": 1}), json!({"time": "2020-05-30T00:00:04Z", "n": 4})]);
+        let b = stream(vec![
+            json!({"time": "2020-05-30T00:00:02Z", "n": 2}),
+            json!({"n": 3}),
+            json!({"time": "2020-05-30T00:00:05Z", "n": 5}),
+        ]);
+        let merged: Vec<Value> = MergeByTime::new(vec![a, b], Order::Oldest).map(|e| e["n"].clone()).collect();
+        assert_eq!(vec![json!(1), json!(2), json!(3), json!(4), json!(5)], merged);
+    }
+
+    #[test]
+    fn interleaves_reversed_streams_newest_first() {
+        let a = stream(vec![json!({"time": "2020-05-30T00:00:04Z", "n": 4}), json!({"time": "2020-05-30T00:00:01Z", "n": 1})]);
+        let b = stream(vec![json!({"_time": "2020-05-30T00:00:03.5Z", "n": 3}), json!({"time": 1_590_796_802, "n": 2})]);
+        let merged: Vec<Value> = MergeByTime::new(vec![a, b], Order::Newest).map(|e| e["n"].clone()).collect();
+        assert_eq!(vec![json!(4), json!(3), json!(2), json!(1)], merged);
+    }
+
+    #[test]
+    fn puts_stragglers_in_place() {
+        let a = stream(vec![
+            json!({"time": "2020-05-30T00:00:02Z", "n": 2}),
+            json!({"time": "2020-05-30T00:00:01Z", "n": 1}),
+            json!({"time": "2020-05-30T00:00:03Z", "n": 3}),
+        ]);
+        let merged: Vec<Value> = MergeByTime::new(vec![a], Order::Oldest).map(|e| e["n"].clone()).collect();
+        assert_eq!(vec![json!(1), json!(2), json!(3)], merged);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::decoder::Format;
+use crate::multiline::Multiline;
+use crate::preset::Preset;
+use crate::source::{fill, LogFile, SourceOptions};
+use crate::timestamp::Zone;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+lazy_static! {
+    static ref ROTATED: Regex = Regex::new(r"^(.*\.log)(\.[0-9]+)?(\.gz|\.zst)?$").unwrap();
+}
+
+// `app.log.meta` next to `app.log`, in json or toml:
+//
+//     type = "cri"                      # json, text, logfmt, cri or docker
+//     parsers = ["nginx"]               # see `Preset`
+//     multiline = "start:^\\d{4}-"      # see `Multiline::parse`
+//     timezone = "Europe/Rome"          # for times written without an offset
+//     raw_lines = false                 # drop lines that aren't json instead of keeping them as `_raw`
+//     [fields]                          # added to every event
+//     env = "prod"
+//
+// rotations of a file share its sidecar unless they have their own
+pub fn sidecar(path: &Path) -> Option<PathBuf> {
+    let own = PathBuf::from(format!("{}.meta", path.display()));
+    if own.is_file() {
+        return Some(own);
+    }
+    let name = path.file_name()?.to_string_lossy();
+    let base = ROTATED.captures(&name)?;
+    let shared = path.with_file_name(format!("{}.meta", &base[1]));
+    if shared != own && shared.is_file() {
+        Some(shared)
+    } else {
+        None
+    }
+}
+
+fn read(text: &str) -> Result<Value, String> {
+    if text.trim_start().starts_with('{') {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    } else {
+        let table: toml::Value = toml::from_str(text).map_err(|e| e.to_string())?;
+        serde_json::to_value(table).map_err(|e| e.to_string())
+    }
+}
+
+fn string<'v>(key: &str, value: &'v Value) -> Result<&'v str, String> {
+    value.as_str().ok_or_else(|| format!("`{}` should be a string", key))
+}
+
+pub fn parse(text: &str, options: &mut SourceOptions) -> Result<(), String> {
+    let meta = match read(text)? {
+        Value::Object(meta) => meta,
+        _ => return Err("expected a table of settings".to_owned()),
+    };
+    for (key, value) in &meta {
+        match key.as_str() {
+            "type" => {
+                let name = string(key, value)?;
+                options.format =
+                    Format::from_name(name).ok_or_else(|| format!("unknown type `{}`, expected one of json, text, logfmt, cri, docker", name))?;
+            }
+            "parsers" => {
+                let names = value.as_array().ok_or("`parsers` should be a list")?;
+                options.presets = names
+                    .iter()
+                    .map(|name| {
+                        let name = string(key, name)?;
+                        Preset::from_name(name).ok_or_else(|| format!("unknown parser `{}`, expected one of nginx, logfmt, json", name))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "multiline" => options.multiline = Some(Multiline::parse(string(key, value)?)?),
+            "timezone" => {
+                let name = string(key, value)?;
+                options.timezone = Some(Zone::parse(name).ok_or_else(|| format!("unknown timezone `{}`", name))?);
+            }
+            "raw_lines" => options.raw_lines = value.as_bool().ok_or("`raw_lines` should be true or false")?,
+            "fields" => {
+                let mut fields = match value {
+                    Value::Object(_) => value.clone(),
+                    _ => return Err("`fields` should be a table".to_owned()),
+                };
+                fill(&mut fields, &options.fields);
+                if let Value::Object(fields) = fields {
+                    options.fields = fields;
+                }
+            }
+            other => return Err(format!("unknown setting `{}`", other)),
+        }
+    }
+    Ok(())
+}
+
+// settles the file's options with its sidecar, if it has one; a broken one changes nothing
+pub fn apply(file: &mut LogFile) -> Result<(), String> {
+    let sidecar = match sidecar(&file.path) {
+        Some(sidecar) => sidecar,
+        None => return Ok(()),
+    };
+    let mut options = file.options.clone();
+    fs::read_to_string(&sidecar)
+        .map_err(|e| e.to_string())
+        .and_then(|text| parse(&text, &mut options))
+        .map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+    file.options = options;
+    Ok(())
+}
+
+// when a sidecar was last modified and how long it was then
+type Version = Option<(SystemTime, u64)>;
+
+// the sidecar a file was settled with, its version and what came of it
+type Settled = (PathBuf, Version, Result<SourceOptions, String>);
+
+// sidecars as they were last read: they are only parsed again once they change, and a broken
+// one is reported the first time it is seen rather than on every search
+#[derive(Default)]
+pub struct Sidecars {
+    settled: Mutex<HashMap<PathBuf, Settled>>,
+    reported: Mutex<HashSet<(PathBuf, Version)>>,
+}
+
+impl Sidecars {
+    pub fn apply(&self, file: &mut LogFile) {
+        let sidecar = match sidecar(&file.path) {
+            Some(sidecar) => sidecar,
+            None => return,
+        };
+        let modified = fs::metadata(&sidecar)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        let mut settled = self.settled.lock().unwrap();
+        let fresh = match settled.get(&file.path) {
+            Some((path, at, _)) => *path != sidecar || *at != modified,
+            None => true,
+        };
+        if fresh {
+            let mut read = file.clone();
+            let result = apply(&mut read).map(|()| read.options);
+            if let Err(e) = &result {
+                if self.reported.lock().unwrap().insert((sidecar.clone(), modified)) {
+                    eprintln!("ignoring sidecar {}", e);
+                }
+            }
+            settled.insert(file.path.clone(), (sidecar, modified, result));
+        }
+        if let Some((_, _, Ok(options))) = settled.get(&file.path) {
+            file.options = options.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, sidecar, Sidecars};
+    use crate::decoder::Format;
+    use crate::preset::Preset;
+    use crate::source::{LogFile, SourceOptions};
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn reads_toml_and_json_alike() {
+        let toml = r#"
+            type = "cri"
+            parsers = ["nginx"]
+            multiline = "indented"
+            timezone = "Europe/Rome"
+            [fields]
+            env = "prod"
+            kubernetes = { namespace_name = "kitchen" }
+        "#;
+        let json = r#"{"type": "cri", "parsers": ["nginx"], "multiline": "indented", "timezone": "Europe/Rome",
+            "fields": {"env": "prod", "kubernetes": {"namespace_name": "kitchen"}}}"#;
+        for text in &[toml, json] {
+            let mut options = SourceOptions::default();
+            options.fields = json!({"kubernetes": {"pod_name": "oven-1", "namespace_name": "from-path"}})
+                .as_object()
+                .unwrap()
+                .clone();
+            parse(text, &mut options).unwrap();
+            assert_eq!(Format::Cri, options.format);
+            assert_eq!(vec![Preset::Nginx], options.presets);
+            assert!(options.multiline.is_some() && options.timezone.is_some());
+            assert_eq!(
+                json!({"env": "prod", "kubernetes": {"namespace_name": "kitchen", "pod_name": "oven-1"}}),
+                json!(options.fields)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_know() {
+        let mut options = SourceOptions::default();
+        assert!(parse("type = \"xml\"", &mut options).is_err());
+        assert!(parse("tz = \"UTC\"", &mut options).is_err());
+        assert!(parse("garbage", &mut options).is_err());
+    }
+
+    #[test]
+    fn rotations_share_the_sidecar() {
+        let dir = std::env::temp_dir().join(format!("still-meta-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.log.meta"), "type = \"text\"").unwrap();
+        fs::write(dir.join("app.log.1.meta"), "type = \"json\"").unwrap();
+        assert_eq!(Some(dir.join("app.log.meta")), sidecar(&dir.join("app.log")));
+        assert_eq!(Some(dir.join("app.log.meta")), sidecar(&dir.join("app.log.2.gz")));
+        assert_eq!(Some(dir.join("app.log.1.meta")), sidecar(&dir.join("app.log.1")));
+        assert_eq!(None, sidecar(&dir.join("other.log")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecars_are_read_again_once_changed() {
+        let dir = std::env::temp_dir().join(format!("still-sidecars-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = LogFile {
+            path: dir.join("app.log"),
+            name: "app.log".to_owned(),
+            options: SourceOptions::default(),
+        };
+        let sidecars = Sidecars::default();
+        fs::write(dir.join("app.log.meta"), "type = \"xml\"").unwrap();
+        let mut broken = file.clone();
+        sidecars.apply(&mut broken);
+        assert_eq!(Format::Lines, broken.options.format);
+        assert_eq!(1, sidecars.reported.lock().unwrap().len());
+
+        fs::write(dir.join("app.log.meta"), "type = \"docker\"").unwrap();
+        let mut fixed = file;
+        sidecars.apply(&mut fixed);
+        assert_eq!(Format::DockerJson, fixed.options.format);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use regex::Regex;
+use serde_json::Value;
+
+// no event grows past this many lines, whatever the rule says
+const MAX_LINES: usize = 1000;
+
+// what a line that starts a new event looks like
+#[derive(Clone, Debug)]
+pub enum LineStart {
+    // `^\d{4}-\d{2}-\d{2}` and the like: anything else continues the event before it
+    Pattern(Regex),
+    // indented lines and java's `Caused by:` continue the event before them
+    NotIndented,
+}
+
+impl LineStart {
+    fn is_start(&self, text: &str) -> bool {
+        match self {
+            LineStart::Pattern(pattern) => pattern.is_match(text),
+            LineStart::NotIndented => !text.starts_with(char::is_whitespace) && !text.starts_with("Caused by:"),
+        }
+    }
+}
+
+// how the lines of a source are joined into events, before the search stage sees them
+#[derive(Clone, Debug)]
+pub enum Multiline {
+    // plain text lines, joined with newlines
+    Lines(LineStart),
+    // fluentd records of the same container and stream, `log`s concatenated, whatever other
+    // containers logged in between: a partial record is always continued by the next one of its
+    // stream, a complete one only when the next `log` doesn't start an event
+    Fluentd(Option<LineStart>),
+}
+
+impl Multiline {
+    // `indented`, `start:<regex>`, `fluentd`, `fluentd+indented` or `fluentd+start:<regex>`
+    pub fn parse(spec: &str) -> Result<Multiline, String> {
+        fn line_start(spec: &str) -> Result<LineStart, String> {
+            if spec == "indented" {
+                Ok(LineStart::NotIndented)
+            } else if spec.starts_with("start:") {
+                Regex::new(&spec["start:".len()..])
+                    .map(LineStart::Pattern)
+                    .map_err(|e| e.to_string())
+            } else {
+                Err(format!("unknown multiline rule `{}`, expected indented or start:<regex>", spec))
+            }
+        }
+        if spec == "fluentd" {
+            Ok(Multiline::Fluentd(None))
+        } else if spec.starts_with("fluentd+") {
+            line_start(&spec["fluentd+".len()..]).map(|start| Multiline::Fluentd(Some(start)))
+        } else {
+            line_start(spec).map(Multiline::Lines)
+        }
+    }
+
+    fn piece(&self, line: String) -> Piece {
+        let record = match self {
+            Multiline::Lines(_) => None,
+            Multiline::Fluentd(_) => serde_json::from_str(&line).ok().filter(Value::is_object),
+        };
+        Piece { line, record }
+    }
+
+    // the stream a piece is joined within; pieces without one are events of their own
+    fn stream(&self, piece: &Piece) -> Option<String> {
+        match self {
+            Multiline::Lines(_) => Some(String::new()),
+            Multiline::Fluentd(_) => {
+                let record = piece.record.as_ref()?;
+                let stream = record.get("stream").and_then(Value::as_str).unwrap_or("");
+                container(record).map(|container| format!("{}/{}", container, stream))
+            }
+        }
+    }
+
+    // whether `later` belongs to the same event as `earlier`, the piece of its stream right before
+    // it in the file
+    fn continues(&self, earlier: &Piece, later: &Piece) -> bool {
+        match self {
+            Multiline::Lines(start) => !start.is_start(&later.line),
+            Multiline::Fluentd(start) => match (&earlier.record, &later.record) {
+                (Some(earlier), Some(later)) => {
+                    is_partial(earlier)
+                        || start
+                            .as_ref()
+                            .map_or(false, |start| !start.is_start(log(later).trim_end_matches('\n')))
+                }
+                _ => false,
+            },
+        }
+    }
+
+    // the pieces of one event, in file order
+    fn join(&self, mut pieces: Vec<Piece>) -> String {
+        if pieces.len() == 1 {
+            return pieces.remove(0).line;
+        }
+        match self {
+            Multiline::Lines(_) => pieces
+                .into_iter()
+                .map(|piece| piece.line)
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Multiline::Fluentd(_) => {
+                let joined: String = pieces.iter().filter_map(|piece| piece.record.as_ref()).map(log).collect();
+                let mut event = pieces.remove(0).record.unwrap();
+                event["log"] = Value::String(joined);
+                event.to_string()
+            }
+        }
+    }
+}
+
+fn container(record: &Value) -> Option<&Value> {
+    record.pointer("/kubernetes/docker_id").or_else(|| record.get("container_id"))
+}
+
+fn log(record: &Value) -> &str {
+    record.get("log").and_then(Value::as_str).unwrap_or("")
+}
+
+// docker's fluentd driver marks the pieces of a long line with `partial_message`, cri with a `P` logtag
+fn is_partial(record: &Value) -> bool {
+    let flag = |name: &str| match record.get(name) {
+        Some(Value::Bool(b)) => *b,
+        Some(Value::String(s)) => s == "true",
+        _ => false,
+    };
+    (flag("partial_message") && !flag("partial_last")) || record.get("logtag").and_then(Value::as_str) == Some("P")
+}
+
+struct Piece {
+    line: String,
+    record: Option<Value>,
+}
+
+// the pieces of an event so far, in reading order
+struct Group {
+    stream: Option<String>,
+    pieces: Vec<Piece>,
+    closed: bool,
+    // where the event starts in the file, counted in pieces read: the first one read, or when
+    // reading backwards the last
+    at: usize,
+}
+
+struct Assembled<'a> {
+    pieces: Box<dyn Iterator<Item = Piece> + 'a>,
+    rule: &'a Multiline,
+    backwards: bool,
+    // events still being assembled, a few when streams interleave
+    groups: Vec<Group>,
+    buffered: usize,
+    read: usize,
+}
+
+impl<'a> Assembled<'a> {
+    fn add(&mut self, piece: Piece) {
+        let at = self.read;
+        self.read += 1;
+        self.buffered += 1;
+        let stream = self.rule.stream(&piece);
+        let (rule, backwards) = (self.rule, self.backwards);
+        let open = self
+            .groups
+            .iter_mut()
+            .find(|group| !group.closed && stream.is_some() && group.stream == stream);
+        if let Some(group) = open {
+            let last = group.pieces.last().unwrap();
+            // read backwards the group so far comes after the piece in the file
+            let joins = if backwards { rule.continues(&piece, last) } else { rule.continues(last, &piece) };
+            if joins && group.pieces.len() < MAX_LINES {
+                group.pieces.push(piece);
+                if backwards {
+                    group.at = at;
+                }
+                return;
+            }
+            group.closed = true;
+        }
+        let closed = stream.is_none();
+        self.groups.push(Group { stream, pieces: vec![piece], closed, at });
+    }
+
+    // the event that comes first, when it can't grow anymore
+    fn take_first(&mut self) -> Option<Group> {
+        let first = (0..self.groups.len()).min_by_key(|&i| self.groups[i].at)?;
+        if !self.groups[first].closed {
+            return None;
+        }
+        let group = self.groups.remove(first);
+        self.buffered -= group.pieces.len();
+        Some(group)
+    }
+}
+
+impl<'a> Iterator for Assembled<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(mut group) = self.take_first() {
+                if self.backwards {
+                    group.pieces.reverse();
+                }
+                return Some(self.rule.join(group.pieces));
+            }
+            match self.pieces.next() {
+                Some(piece) => self.add(piece),
+                None if self.groups.is_empty() => return None,
+                None => self.groups.iter_mut().for_each(|group| group.closed = true),
+            }
+            // a stream that never finishes its event doesn't hold back the others forever
+            if self.buffered > MAX_LINES {
+                if let Some(first) = self.groups.iter_mut().min_by_key(|group| group.at) {
+                    first.closed = true;
+                }
+            }
+        }
+    }
+}
+
+// `lines` are in file order, or last to first when `backwards`; events come out the same way
+pub fn assemble<'a>(
+    lines: Box<dyn Iterator<Item = String> + 'a>,
+    rule: &'a Multiline,
+    backwards: bool,
+) -> Box<dyn Iterator<Item = String> + 'a> {
+    let pieces: Box<dyn Iterator<Item = Piece> + 'a> = Box::new(lines.map(move |line| rule.piece(line)));
+    Box::new(Assembled {
+        pieces,
+        rule,
+        backwards,
+        groups: vec![],
+        buffered: 0,
+        read: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, Multiline};
+    use serde_json::{json, Value};
+
+    fn run(rule: &str, lines: &[&str], backwards: bool) -> Vec<String> {
+        let rule = Multiline::parse(rule).unwrap();
+        let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        if backwards {
+            lines.reverse();
+        }
+        let mut events: Vec<String> = assemble(Box::new(lines.into_iter()), &rule, backwards).collect();
+        if backwards {
+            events.reverse();
+        }
+        events
+    }
+
+    #[test]
+    fn joins_stack_traces_both_ways() {
+        let lines = [
+            "2020-05-30 09:51:27 ERROR boom",
+            "java.lang.IllegalStateException: boom",
+            "\tat kitchen.Oven.bake(Oven.java:12)",
+            "2020-05-30 09:51:28 INFO fine",
+        ];
+        let expected = vec![
+            "2020-05-30 09:51:27 ERROR boom\njava.lang.IllegalStateException: boom\n\tat kitchen.Oven.bake(Oven.java:12)".to_owned(),
+            "2020-05-30 09:51:28 INFO fine".to_owned(),
+        ];
+        assert_eq!(expected, run(r"start:^\d{4}-\d{2}-\d{2}", &lines, false));
+        assert_eq!(expected, run(r"start:^\d{4}-\d{2}-\d{2}", &lines, true));
+        assert_eq!(3, run("indented", &lines, false).len());
+    }
+
+    #[test]
+    fn joins_fluentd_records_of_one_container() {
+        let record = |log: &str, id: &str, partial: bool| {
+            json!({"log": log, "kubernetes": {"docker_id": id}, "partial_message": partial.to_string()}).to_string()
+        };
+        let lines = [
+            record("Traceback (most recent call last):\n", "a", false),
+            record("  File \"app.py\", line 1\n", "a", false),
+            record("GET / 200\n", "b", false),
+            record("  raise Val", "a", true),
+            record("ueError\n", "a", false),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        for backwards in &[false, true] {
+            let logs: Vec<Value> = run("fluentd+indented", &lines, *backwards)
+                .iter()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap()["log"].clone())
+                .collect();
+            assert_eq!(
+                vec![
+                    json!("Traceback (most recent call last):\n  File \"app.py\", line 1\n  raise ValueError\n"),
+                    json!("GET / 200\n"),
+                ],
+                logs
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_the_streams_of_a_container_apart() {
+        let record = |log: &str, stream: &str, partial: bool| {
+            json!({"log": log, "stream": stream, "container_id": "a", "partial_message": partial}).to_string()
+        };
+        let lines = [
+            record("out ", "stdout", true),
+            record("err\n", "stderr", false),
+            record("done\n", "stdout", false),
+            "not a record".to_owned(),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        for backwards in &[false, true] {
+            let events = run("fluentd", &lines, *backwards);
+            let logs: Vec<Value> = events[..2]
+                .iter()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap()["log"].clone())
+                .collect();
+            assert_eq!(vec![json!("out done\n"), json!("err\n")], logs);
+            assert_eq!("not a record", events[2]);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::ingest::{body_text, gunzip, BodyError, Rejected};
+use crate::timestamp;
+use chrono::{TimeZone, Utc};
+use prost::{Message, Oneof};
+use serde_json::{json, Map, Value};
+
+// the parts of opentelemetry's logs.proto that still reads
+#[derive(Clone, PartialEq, Message)]
+struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    severity_number: i32,
+    #[prost(string, tag = "3")]
+    severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    attributes: Vec<KeyValue>,
+    #[prost(bytes, tag = "9")]
+    trace_id: Vec<u8>,
+    #[prost(bytes, tag = "10")]
+    span_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AnyValue {
+    #[prost(oneof = "Any", tags = "1, 2, 3, 4, 5, 6, 7")]
+    value: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, Oneof)]
+enum Any {
+    #[prost(string, tag = "1")]
+    String(String),
+    #[prost(bool, tag = "2")]
+    Bool(bool),
+    #[prost(int64, tag = "3")]
+    Int(i64),
+    #[prost(double, tag = "4")]
+    Double(f64),
+    #[prost(message, tag = "5")]
+    Array(ArrayValue),
+    #[prost(message, tag = "6")]
+    Kvlist(KeyValueList),
+    #[prost(bytes, tag = "7")]
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    values: Vec<KeyValue>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// protobuf values in their otlp/json encoding, so that both bodies are read by the same code
+fn any_to_otlp_json(value: &AnyValue) -> Value {
+    match &value.value {
+        None => json!({}),
+        Some(Any::String(s)) => json!({ "stringValue": s }),
+        Some(Any::Bool(b)) => json!({ "boolValue": b }),
+        Some(Any::Int(i)) => json!({ "intValue": i.to_string() }),
+        Some(Any::Double(f)) => json!({ "doubleValue": f }),
+        Some(Any::Array(array)) => json!({ "arrayValue": { "values": array.values.iter().map(any_to_otlp_json).collect::<Vec<_>>() } }),
+        Some(Any::Kvlist(list)) => json!({ "kvlistValue": { "values": attributes_to_otlp_json(&list.values) } }),
+        Some(Any::Bytes(b)) => json!({ "bytesValue": base64::encode(b) }),
+    }
+}
+
+fn attributes_to_otlp_json(attributes: &[KeyValue]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|kv| json!({ "key": kv.key, "value": kv.value.as_ref().map_or(json!({}), any_to_otlp_json) }))
+        .collect()
+}
+
+fn to_otlp_json(request: &ExportLogsServiceRequest) -> Value {
+    let resource_logs: Vec<Value> = request
+        .resource_logs
+        .iter()
+        .map(|resource_logs| {
+            let attributes = resource_logs.resource.as_ref().map_or(&[][..], |r| r.attributes.as_slice());
+            let scope_logs: Vec<Value> = resource_logs
+                .scope_logs
+                .iter()
+                .map(|scope_logs| {
+                    let records: Vec<Value> = scope_logs
+                        .log_records
+                        .iter()
+                        .map(|record| {
+                            json!({
+                                "timeUnixNano": record.time_unix_nano.to_string(),
+                                "observedTimeUnixNano": record.observed_time_unix_nano.to_string(),
+                                "severityNumber": record.severity_number,
+                                "severityText": record.severity_text,
+                                "body": record.body.as_ref().map_or(Value::Null, any_to_otlp_json),
+                                "attributes": attributes_to_otlp_json(&record.attributes),
+                                "traceId": hex(&record.trace_id),
+                                "spanId": hex(&record.span_id),
+                            })
+                        })
+                        .collect();
+                    let scope = scope_logs.scope.as_ref().map(|s| json!({ "name": s.name, "version": s.version }));
+                    json!({ "scope": scope, "logRecords": records })
+                })
+                .collect();
+            json!({ "resource": { "attributes": attributes_to_otlp_json(attributes) }, "scopeLogs": scope_logs })
+        })
+        .collect();
+    json!({ "resourceLogs": resource_logs })
+}
+
+// an otlp/json AnyValue as plain json
+fn value(any: &Value) -> Value {
+    let (kind, value) = match any.as_object().and_then(|any| any.iter().next()) {
+        Some(pair) => pair,
+        None => return Value::Null,
+    };
+    match kind.as_str() {
+        "intValue" => match value {
+            Value::String(s) => s.parse::<i64>().map_or_else(|_| value.clone(), |i| json!(i)),
+            _ => value.clone(),
+        },
+        "arrayValue" => Value::Array(value["values"].as_array().map_or(vec![], |values| values.iter().map(self::value).collect())),
+        "kvlistValue" => Value::Object(attributes(&value["values"])),
+        _ => value.clone(),
+    }
+}
+
+fn attributes(list: &Value) -> Map<String, Value> {
+    list.as_array()
+        .map_or(&[][..], Vec::as_slice)
+        .iter()
+        .filter_map(|kv| Some((kv["key"].as_str()?.to_owned(), value(&kv["value"]))))
+        .collect()
+}
+
+fn severity(number: i64) -> Option<&'static str> {
+    match number {
+        1..=4 => Some("TRACE"),
+        5..=8 => Some("DEBUG"),
+        9..=12 => Some("INFO"),
+        13..=16 => Some("WARN"),
+        17..=20 => Some("ERROR"),
+        21..=24 => Some("FATAL"),
+        _ => None,
+    }
+}
+
+fn unix_nanos(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_u64(),
+    }
+    .filter(|nanos| *nanos > 0)
+}
+
+// a log record as an event: its attributes as fields, a string body as `message` and any other
+// as `body`, and where it came from under `resource` and `scope`. those, the time, severity and
+// ids are the record's own: an attribute of the same name gives way to them
+fn event(record: &Value, resource: &Map<String, Value>, scope: &Value) -> Result<Value, String> {
+    let record = record.as_object().ok_or("a log record should be an object")?;
+    let mut event = attributes(&record.get("attributes").cloned().unwrap_or(Value::Null));
+    let nanos = record
+        .get("timeUnixNano")
+        .and_then(unix_nanos)
+        .or_else(|| record.get("observedTimeUnixNano").and_then(unix_nanos))
+        .ok_or("a log record without a time")?;
+    let time = Utc.timestamp_opt((nanos / 1_000_000_000) as i64, (nanos % 1_000_000_000) as u32).single().ok_or("a time out of range")?;
+    event.insert("time".to_owned(), json!(timestamp::canonical(&time)));
+    let number = record.get("severityNumber").and_then(Value::as_i64).unwrap_or(0);
+    let text = record.get("severityText").and_then(Value::as_str).filter(|text| !text.is_empty());
+    if let Some(severity) = text.or_else(|| severity(number)) {
+        event.insert("severity".to_owned(), json!(severity));
+    }
+    if number > 0 {
+        event.insert("severity_number".to_owned(), json!(number));
+    }
+    match record.get("body").map(value) {
+        Some(Value::String(message)) => {
+            event.insert("message".to_owned(), json!(message));
+        }
+        Some(Value::Null) | None => {}
+        Some(body) => {
+            event.insert("body".to_owned(), body);
+        }
+    }
+    for (field, name) in &[("trace_id", "traceId"), ("span_id", "spanId")] {
+        if let Some(id) = record.get(*name).and_then(Value::as_str).filter(|id| !id.is_empty()) {
+            event.insert((*field).to_owned(), json!(id.to_lowercase()));
+        }
+    }
+    if !resource.is_empty() {
+        event.insert("resource".to_owned(), Value::Object(resource.clone()));
+    }
+    if scope.is_object() {
+        event.insert("scope".to_owned(), scope.clone());
+    }
+    event.insert("_source".to_owned(), json!("otlp"));
+    Ok(Value::Object(event))
+}
+
+fn events(request: &Value) -> Result<(Vec<Value>, Rejected), String> {
+    let resource_logs = request["resourceLogs"].as_array().ok_or("`resourceLogs` should be a list")?;
+    let mut events = vec![];
+    let mut rejected = vec![];
+    let mut index = 0;
+    for resource_logs in resource_logs {
+        let resource = attributes(&resource_logs["resource"]["attributes"]);
+        for scope_logs in resource_logs["scopeLogs"].as_array().map_or(&[][..], Vec::as_slice) {
+            let scope = &scope_logs["scope"];
+            for record in scope_logs["logRecords"].as_array().map_or(&[][..], Vec::as_slice) {
+                match event(record, &resource, scope) {
+                    Ok(event) => events.push(event),
+                    Err(e) => rejected.push((index, e)),
+                }
+                index += 1;
+            }
+        }
+    }
+    Ok((events, rejected))
+}
+
+// the body of a `POST /v1/logs`, an `ExportLogsServiceRequest` in protobuf or json as its content
+// type says, gzipped or not. records are numbered across resources and scopes in the rejections
+pub fn decode_logs(body: &[u8], protobuf: bool, limit: u64) -> Result<(Vec<Value>, Rejected), BodyError> {
+    let request = if protobuf {
+        let request = ExportLogsServiceRequest::decode(gunzip(body, limit)?.as_ref()).map_err(|e| format!("can't read the export request: {}", e))?;
+        to_otlp_json(&request)
+    } else {
+        serde_json::from_str(&body_text(body, limit)?).map_err(|e| format!("can't read the export request: {}", e))?
+    };
+    Ok(events(&request)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_logs, Any, AnyValue, ExportLogsServiceRequest, InstrumentationScope, KeyValue, LogRecord, Resource, ResourceLogs, ScopeLogs};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use prost::Message;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn string(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_owned(),
+            value: Some(AnyValue { value: Some(Any::String(value.to_owned())) }),
+        }
+    }
+
+    #[test]
+    fn flattens_protobuf_records() {
+        let request = ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource { attributes: vec![string("service.name", "oven")] }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope { name: "baker".to_owned(), version: "1.0".to_owned() }),
+                    log_records: vec![
+                        LogRecord {
+                            time_unix_nano: 1_590_832_287_500_000_000,
+                            severity_number: 17,
+                            body: Some(AnyValue { value: Some(Any::String("burnt".to_owned())) }),
+                            attributes: vec![
+                                KeyValue {
+                                    key: "tray".to_owned(),
+                                    value: Some(AnyValue { value: Some(Any::Int(3)) }),
+                                },
+                                string("message", "gives way to the body"),
+                            ],
+                            trace_id: vec![0xab; 16],
+                            span_id: vec![0x01; 8],
+                            ..LogRecord::default()
+                        },
+                        LogRecord::default(),
+                    ],
+                }],
+            }],
+        };
+        let mut body = vec![];
+        request.encode(&mut body).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&body).unwrap();
+        assert_eq!(decode_logs(&body, true, 1024), decode_logs(&gzip.finish().unwrap(), true, 1024));
+        let (events, rejected) = decode_logs(&body, true, 1024).unwrap();
+        assert_eq!(
+            vec![json!({
+                "tray": 3,
+                "time": "2020-05-30T09:51:27.500000000Z",
+                "severity": "ERROR",
+                "severity_number": 17,
+                "message": "burnt",
+                "trace_id": "abababababababababababababababab",
+                "span_id": "0101010101010101",
+                "resource": {"service.name": "oven"},
+                "scope": {"name": "baker", "version": "1.0"},
+                "_source": "otlp",
+            })],
+            events
+        );
+        assert_eq!(vec![1], rejected.iter().map(|(index, _)| *index).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn flattens_json_records() {
+        let body = r#"{"resourceLogs": [{"resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "oven"}}]},
+            "scopeLogs": [{"logRecords": [{"observedTimeUnixNano": "1590832287500000000", "severityText": "Warning",
+                "body": {"kvlistValue": {"values": [{"key": "trays", "value": {"arrayValue": {"values": [{"intValue": "1"}, {"boolValue": true}]}}}]}},
+                "traceId": "5B8EFFF798038103D269B633813FC60C"}]}]}]}"#;
+        let (events, rejected) = decode_logs(body.as_bytes(), false, 1024).unwrap();
+        assert_eq!(
+            vec![json!({
+                "time": "2020-05-30T09:51:27.500000000Z",
+                "severity": "Warning",
+                "body": {"trays": [1, true]},
+                "trace_id": "5b8efff798038103d269b633813fc60c",
+                "resource": {"service.name": "oven"},
+                "_source": "otlp",
+            })],
+            events
+        );
+        assert!(rejected.is_empty());
+        assert!(decode_logs(b"{}", false, 1024).is_err());
+    }
+}
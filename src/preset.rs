@@ -0,0 +1,116 @@
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+lazy_static! {
+    // nginx's `combined` access log format
+    static ref NGINX_COMBINED: Regex = Regex::new(
+        r#"^(?P<remote_addr>\S+) - (?P<remote_user>\S+) \[(?P<time_local>[^\]]+)\] "(?P<method>\S+) (?P<path>\S+) (?P<protocol>[^"]+)" (?P<status>[0-9]{3}) (?P<body_bytes_sent>[0-9]+|-) "(?P<http_referer>[^"]*)" "(?P<http_user_agent>[^"]*)""#
+    )
+    .unwrap();
+    static ref LOGFMT_PAIR: Regex = Regex::new(r#"(?:^|\s)([^\s="]+)=(?:"((?:[^"\\]|\\.)*)"|(\S*))"#).unwrap();
+}
+
+// the `key=value key="quoted value"` pairs of a logfmt line, values as strings
+pub fn parse_logfmt(text: &str) -> Map<String, Value> {
+    LOGFMT_PAIR
+        .captures_iter(text)
+        .map(|cap| {
+            let value = match cap.get(2) {
+                Some(quoted) => quoted.as_str().replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => cap[3].to_owned(),
+            };
+            (cap[1].to_owned(), Value::String(value))
+        })
+        .collect()
+}
+
+fn parse_nginx(text: &str) -> Map<String, Value> {
+    let cap = match NGINX_COMBINED.captures(text) {
+        Some(cap) => cap,
+        None => return Map::new(),
+    };
+    let number = |name: &str| cap[name].parse::<u64>().map(|n| json!(n)).unwrap_or(Value::Null);
+    let fields = json!({
+        "remote_addr": &cap["remote_addr"],
+        "remote_user": &cap["remote_user"],
+        "time_local": &cap["time_local"],
+        "method": &cap["method"],
+        "path": &cap["path"],
+        "protocol": &cap["protocol"],
+        "status": number("status"),
+        "body_bytes_sent": number("body_bytes_sent"),
+        "http_referer": &cap["http_referer"],
+        "http_user_agent": &cap["http_user_agent"],
+    });
+    fields.as_object().unwrap().clone()
+}
+
+// a parser a source can name instead of spelling out a `parse` in every query. it reads the
+// event's message (`log`, `_raw` or `message`) and adds the fields it finds, never replacing any
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    Nginx,
+    Logfmt,
+    // a message that is itself a json object
+    Json,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name {
+            "nginx" => Some(Preset::Nginx),
+            "logfmt" => Some(Preset::Logfmt),
+            "json" => Some(Preset::Json),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, event: &mut Value) {
+        let message = match ["log", "_raw", "message"].iter().find_map(|field| event.get(field).and_then(Value::as_str)) {
+            Some(message) => message.trim_end_matches('\n'),
+            None => return,
+        };
+        let fields = match self {
+            Preset::Nginx => parse_nginx(message),
+            Preset::Logfmt => parse_logfmt(message),
+            Preset::Json => match serde_json::from_str(message) {
+                Ok(Value::Object(fields)) => fields,
+                _ => Map::new(),
+            },
+        };
+        if let Value::Object(event) = event {
+            for (key, value) in fields {
+                event.entry(key).or_insert(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_logfmt, Preset};
+    use serde_json::json;
+
+    #[test]
+    fn reads_logfmt_pairs() {
+        let fields = parse_logfmt(r#"time="2020-05-30T00:01:19Z" level=info msg="say \"hi\"" region=lon1"#);
+        assert_eq!(
+            json!({"time": "2020-05-30T00:01:19Z", "level": "info", "msg": "say \"hi\"", "region": "lon1"}),
+            json!(fields)
+        );
+    }
+
+    #[test]
+    fn nginx_preset_reads_access_logs() {
+        let mut event = json!({
+            "log": "10.244.0.70 - - [30/May/2020:09:51:27 +0000] \"GET /icons/seventh_son.png HTTP/1.1\" 200 2172 \"https://ron.gg/\" \"Mozilla/5.0\" \"167.99.194.225\"\n",
+            "method": "kept",
+        });
+        Preset::Nginx.apply(&mut event);
+        assert_eq!(json!("kept"), event["method"]);
+        assert_eq!(json!("/icons/seventh_son.png"), event["path"]);
+        assert_eq!(json!(200), event["status"]);
+        assert_eq!(json!(2172), event["body_bytes_sent"]);
+        assert_eq!(json!("Mozilla/5.0"), event["http_user_agent"]);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::literal::LiteralError;
+use lalrpop_util::ParseError;
+use serde_json::json;
+use serde_json::Value;
+use std::fmt::Display;
+
+// a query that could not be turned into a search, pinned to where in the source it went wrong
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    pub kind: &'static str,
+    pub message: String,
+    pub offset: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub expected: Vec<String>,
+    pub snippet: String,
+}
+
+impl QueryError {
+    pub fn new(query: &str, kind: &'static str, message: String, span: (usize, usize), expected: Vec<String>) -> QueryError {
+        let (offset, end) = span;
+        let (line, column) = line_and_column(query, offset);
+        QueryError {
+            kind,
+            message,
+            offset,
+            end,
+            line,
+            column,
+            expected,
+            snippet: snippet(query, offset, end),
+        }
+    }
+
+    // for errors found on the AST, at the span the parser gave what is wrong
+    pub fn at(query: &str, span: (usize, usize), kind: &'static str, message: String) -> QueryError {
+        QueryError::new(query, kind, message, span, vec![])
+    }
+
+    pub fn from_parse_error<T: Display>(query: &str, error: ParseError<usize, T, LiteralError>) -> QueryError {
+        match error {
+            ParseError::InvalidToken { location } => QueryError::new(
+                query,
+                "invalid_token",
+                format!("invalid token at {}", location),
+                (location, next_char_boundary(query, location)),
+                vec![],
+            ),
+            ParseError::UnrecognizedEOF { location, expected } => QueryError::new(
+                query,
+                "unrecognized_eof",
+                "unexpected end of query".to_owned(),
+                (location, location),
+                expected,
+            ),
+            ParseError::UnrecognizedToken { token: (start, token, end), expected } => QueryError::new(
+                query,
+                "unrecognized_token",
+                format!("unexpected token {:?}", token.to_string()),
+                (start, end),
+                expected,
+            ),
+            ParseError::ExtraToken { token: (start, token, end) } => QueryError::new(
+                query,
+                "extra_token",
+                format!("extra token {:?}", token.to_string()),
+                (start, end),
+                vec![],
+            ),
+            ParseError::User { error } => QueryError::new(
+                query,
+                "invalid_literal",
+                error.message,
+                (error.offset, error.end),
+                vec![],
+            ),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind,
+            "message": self.message,
+            "offset": self.offset,
+            "end": self.end,
+            "line": self.line,
+            "column": self.column,
+            "expected": self.expected,
+            "snippet": self.snippet,
+        })
+    }
+}
+
+fn next_char_boundary(query: &str, offset: usize) -> usize {
+    query[offset..].chars().next().map_or(offset, |c| offset + c.len_utf8())
+}
+
+// 1-based, column counted in chars so the caret lines up with what the user typed
+fn line_and_column(query: &str, offset: usize) -> (usize, usize) {
+    let before = &query[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+fn snippet(query: &str, offset: usize, end: usize) -> String {
+    let line_start = query[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = query[offset..].find('\n').map_or(query.len(), |i| offset + i);
+    let source_line = &query[line_start..line_end];
+    let padding: String = query[line_start..offset]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = query[offset..end.min(line_end).max(offset)].chars().count().max(1);
+    format!("{}\n{}{}", source_line, padding, "^".repeat(width))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryError;
+    use crate::search::SearchParser;
+
+    #[test]
+    fn unrecognized_token_points_at_the_token() {
+        let query = "ingress\n| where stream stderr";
+        let error = QueryError::from_parse_error(query, SearchParser::new().parse(query).unwrap_err());
+        assert_eq!("unrecognized_token", error.kind);
+        assert_eq!(23, error.offset);
+        assert_eq!(2, error.line);
+        assert_eq!(16, error.column);
+        assert!(error.expected.contains(&r#""=""#.to_owned()));
+        assert_eq!("| where stream stderr\n               ^^^^^^", error.snippet);
+    }
+
+    #[test]
+    fn unexpected_eof_points_past_the_end() {
+        let query = "ingress | where stream !=";
+        let error = QueryError::from_parse_error(query, SearchParser::new().parse(query).unwrap_err());
+        assert_eq!("unrecognized_eof", error.kind);
+        assert_eq!(query.len(), error.offset);
+        assert_eq!((1, 26), (error.line, error.column));
+        assert_eq!(format!("{}\n{}^", query, " ".repeat(25)), error.snippet);
+    }
+
+    #[test]
+    fn bad_escape_points_at_the_escape() {
+        let query = r#"* | where log = "tab\u{zz}""#;
+        let error = QueryError::from_parse_error(query, SearchParser::new().parse(query).unwrap_err());
+        assert_eq!("invalid_literal", error.kind);
+        assert_eq!((20, 26), (error.offset, error.end));
+        assert_eq!("* | where log = \"tab\\u{zz}\"\n                    ^^^^^^", error.snippet);
+    }
+
+    #[test]
+    fn invalid_token_is_reported() {
+        let query = "ingress ~";
+        let error = QueryError::from_parse_error(query, SearchParser::new().parse(query).unwrap_err());
+        assert_eq!("invalid_token", error.kind);
+        assert_eq!((8, 9), (error.offset, error.end));
+        assert_eq!("ingress ~\n        ^", error.snippet);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::decoder::Format;
+use crate::discovery::Discovery;
+use crate::multiline::Multiline;
+use crate::kubernetes;
+use crate::preset::Preset;
+use crate::timestamp;
+use crate::timestamp::Zone;
+use serde_json::{json, Map, Value};
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::SeekFrom;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+// how the lines of one source become events
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    // lines that aren't a json object become `{"_raw": line, "_source": name}` instead of being dropped
+    pub raw_lines: bool,
+    pub format: Format,
+    pub multiline: Option<Multiline>,
+    pub presets: Vec<Preset>,
+    // the zone of times written without an offset; with one, events get a `_time`
+    pub timezone: Option<Zone>,
+    // added to every event, under the fields it has of its own
+    pub fields: Map<String, Value>,
+}
+
+impl Default for SourceOptions {
+    fn default() -> SourceOptions {
+        SourceOptions {
+            raw_lines: true,
+            format: Format::Lines,
+            multiline: None,
+            presets: vec![],
+            timezone: None,
+            fields: Map::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogFile {
+    pub path: PathBuf,
+    // what `_source` says: the path under the logs dir
+    pub name: String,
+    pub options: SourceOptions,
+}
+
+lazy_static! {
+    // `app.log` and its rotations: `app.log.1`, `app.log.2.gz`, `app.log.gz`, `app.log.zst`
+    static ref LOG_FILE: Regex = Regex::new(r"\.log(\.[0-9]+)?(\.gz|\.zst)?$").unwrap();
+}
+
+pub fn is_log_file(path: &Path) -> bool {
+    path.file_name()
+        .map_or(false, |name| LOG_FILE.is_match(&name.to_string_lossy()))
+}
+
+// the log files under the logs dir, see `Discovery::discover`
+pub fn log_files(dir: &Path, discovery: &Discovery) -> Vec<LogFile> {
+    discovery
+        .discover(dir)
+        .into_iter()
+        .map(|(relative, path)| LogFile {
+            name: relative.to_string_lossy().into_owned(),
+            options: SourceOptions {
+                fields: kubernetes::fields(&path),
+                ..SourceOptions::default()
+            },
+            path,
+        })
+        .collect()
+}
+
+// blank lines are never events
+pub fn to_event(line: &str, file: &LogFile) -> Option<Value> {
+    let options = &file.options;
+    let parsed = match options.format {
+        Format::Text => None,
+        _ => serde_json::from_str(line).ok(),
+    };
+    let mut event = match parsed {
+        // logfmt lines come decoded, with their `_raw` but without a source yet
+        Some(Value::Object(mut event)) if options.format == Format::Logfmt => {
+            event.entry("_source").or_insert_with(|| json!(file.name));
+            Value::Object(event)
+        }
+        Some(event @ Value::Object(_)) => event,
+        _ if options.raw_lines && !line.trim().is_empty() => json!({ "_raw": line, "_source": file.name }),
+        _ => return None,
+    };
+    for preset in &options.presets {
+        preset.apply(&mut event);
+    }
+    fill(&mut event, &options.fields);
+    if let Some(zone) = &options.timezone {
+        if event.get("_time").is_none() {
+            if let Some(time) = timestamp::event_time_in(&event, zone) {
+                event["_time"] = json!(timestamp::canonical(&time));
+            }
+        }
+    }
+    Some(event)
+}
+
+// adds what `event` lacks of `fields`, going into objects both have
+pub fn fill(event: &mut Value, fields: &Map<String, Value>) {
+    if let Value::Object(event) = event {
+        for (key, value) in fields {
+            match event.get_mut(key) {
+                Some(existing) => {
+                    if let Value::Object(nested) = value {
+                        fill(existing, nested);
+                    }
+                }
+                None => {
+                    event.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+fn is_compressed(path: &Path) -> bool {
+    let extension = path.extension();
+    extension == Some(OsStr::new("gz")) || extension == Some(OsStr::new("zst"))
+}
+
+// a read error ends the file: a compressed file that's still being written is cut short. one
+// rotated or compressed away since it was found has no lines
+pub fn read_lines(path: &Path) -> Box<dyn Iterator<Item = String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return skipped(path, e),
+    };
+    let reader: Box<dyn BufRead> = match path.extension().and_then(OsStr::to_str) {
+        Some("gz") => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Some("zst") => match zstd::stream::read::Decoder::new(file) {
+            Ok(decoder) => Box::new(BufReader::new(decoder)),
+            Err(e) => return skipped(path, e),
+        },
+        _ => Box::new(BufReader::new(file)),
+    };
+    Box::new(LossyLines { reader })
+}
+
+fn skipped(path: &Path, error: io::Error) -> Box<dyn Iterator<Item = String>> {
+    eprintln!("skipping {}: {}", path.display(), error);
+    Box::new(iter::empty())
+}
+
+// like `lines()`, but a line that isn't valid utf-8 is read lossily instead of ending the file
+struct LossyLines {
+    reader: Box<dyn BufRead>,
+}
+
+impl Iterator for LossyLines {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut bytes = vec![];
+        match self.reader.read_until(b'\n', &mut bytes) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                if bytes.last() == Some(&b'\n') {
+                    bytes.pop();
+                    if bytes.last() == Some(&b'\r') {
+                        bytes.pop();
+                    }
+                }
+                Some(String::from_utf8_lossy(&bytes).into_owned())
+            }
+        }
+    }
+}
+
+const BACKWARDS_CHUNK: u64 = 64 * 1024;
+
+// the lines of a file last to first, reading it a chunk at a time from the end,
+// so a search that stops early never touches the start of a big file.
+// compressed files can't be read from the end, but they're the old rotations anyway
+pub fn read_lines_backwards(path: &Path) -> Box<dyn Iterator<Item = String>> {
+    if is_compressed(path) {
+        let lines: Vec<String> = read_lines(path).collect();
+        return Box::new(lines.into_iter().rev());
+    }
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return skipped(path, e),
+    };
+    let end = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return skipped(path, e),
+    };
+    Box::new(BackwardsLines {
+        file,
+        pos: end,
+        pending: vec![],
+        at_end: true,
+        done: false,
+    })
+}
+
+struct BackwardsLines {
+    file: File,
+    // pending holds the file's bytes from pos up to the start of the last line handed out
+    pos: u64,
+    pending: Vec<u8>,
+    at_end: bool,
+    done: bool,
+}
+
+impl BackwardsLines {
+    // like `lines()`, a \r is only dropped before a \n, so never from the last line of the file
+    fn line(&self, mut bytes: &[u8]) -> String {
+        if !self.at_end && bytes.last() == Some(&b'\r') {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+impl Iterator for BackwardsLines {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        while !self.done {
+            if let Some(newline) = self.pending.iter().rposition(|b| *b == b'\n') {
+                let bytes = self.pending.split_off(newline + 1);
+                self.pending.truncate(newline);
+                let line = self.line(&bytes);
+                let trailing = self.at_end && bytes.is_empty();
+                self.at_end = false;
+                if !trailing {
+                    return Some(line);
+                }
+            } else if self.pos == 0 {
+                self.done = true;
+                // like `lines()`, an empty file has no lines at all
+                if !(self.at_end && self.pending.is_empty()) {
+                    return Some(self.line(&self.pending));
+                }
+            } else {
+                let size = BACKWARDS_CHUNK.min(self.pos);
+                self.pos -= size;
+                let mut chunk = vec![0; size as usize];
+                // truncated or gone while it's read: the file ends here
+                let read = self.file.seek(SeekFrom::Start(self.pos)).and_then(|_| self.file.read_exact(&mut chunk));
+                if read.is_err() {
+                    self.done = true;
+                    break;
+                }
+                chunk.extend_from_slice(&self.pending);
+                self.pending = chunk;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::Format;
+    use crate::discovery::Discovery;
+    use super::{is_log_file, log_files, read_lines, read_lines_backwards, to_event, LogFile, SourceOptions};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn wraps_lines_that_are_not_json_objects() {
+        let mut file = LogFile {
+            path: PathBuf::from("syslog.log"),
+            name: "syslog.log".to_owned(),
+            options: SourceOptions::default(),
+        };
+        assert_eq!(Some(json!({"a": 1})), to_event(r#"{"a": 1}"#, &file));
+        assert_eq!(Some(json!({"_raw": "garbage", "_source": "syslog.log"})), to_event("garbage", &file));
+        assert_eq!(Some(json!({"_raw": "404", "_source": "syslog.log"})), to_event("404", &file));
+        assert_eq!(None, to_event("  ", &file));
+        file.options.raw_lines = false;
+        assert_eq!(None, to_event("garbage", &file));
+        file.options.format = Format::Logfmt;
+        assert_eq!(
+            Some(json!({"level": "info", "_raw": "level=info", "_source": "syslog.log"})),
+            to_event(r#"{"level": "info", "_raw": "level=info"}"#, &file)
+        );
+    }
+
+    #[test]
+    fn fields_fill_in_what_events_lack() {
+        let mut file = LogFile {
+            path: PathBuf::from("app.log"),
+            name: "app.log".to_owned(),
+            options: SourceOptions::default(),
+        };
+        file.options.fields = json!({"kubernetes": {"namespace_name": "kitchen", "pod_name": "oven-1"}, "env": "prod"})
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(
+            Some(json!({"kubernetes": {"namespace_name": "kitchen", "pod_name": "oven-2"}, "env": "dev"})),
+            to_event(r#"{"kubernetes": {"pod_name": "oven-2"}, "env": "dev"}"#, &file)
+        );
+        assert_eq!(
+            Some(json!({"_raw": "hi", "_source": "app.log", "kubernetes": {"namespace_name": "kitchen", "pod_name": "oven-1"}, "env": "prod"})),
+            to_event("hi", &file)
+        );
+    }
+
+    #[test]
+    fn lists_only_log_files() {
+        let mut names: Vec<String> = log_files(Path::new("fixtures"), &Discovery::default()).into_iter().map(|file| file.name).collect();
+        names.sort();
+        assert_eq!(vec!["mini.sample.1.log", "mini.sample.2.log"], names);
+    }
+
+    #[test]
+    fn recognises_rotated_and_compressed_logs() {
+        for name in &["app.log", "app.log.1", "app.log.gz", "app.log.2.gz", "app.log.zst"] {
+            assert!(is_log_file(Path::new(name)), "{}", name);
+        }
+        for name in &["notlog.log.meta", "app.log.gz.tmp", "log", "app.logs"] {
+            assert!(!is_log_file(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn reads_compressed_files() {
+        let dir = std::env::temp_dir().join(format!("still-compressed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = "one\ntwo\nthree\n";
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(content.as_bytes()).unwrap();
+        fs::write(dir.join("app.log.2.gz"), gz.finish().unwrap()).unwrap();
+        fs::write(dir.join("app.log.1.zst"), zstd::encode_all(content.as_bytes(), 0).unwrap()).unwrap();
+        for name in &["app.log.2.gz", "app.log.1.zst"] {
+            let path = dir.join(name);
+            assert_eq!(vec!["one", "two", "three"], read_lines(&path).collect::<Vec<String>>());
+            assert_eq!(vec!["three", "two", "one"], read_lines_backwards(&path).collect::<Vec<String>>());
+        }
+        // rotated away since it was found
+        for name in &["app.log.3.gz", "app.log.3.zst", "app.log.3"] {
+            assert_eq!(0, read_lines(&dir.join(name)).count());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_fixtures_backwards() {
+        let path = Path::new("fixtures/mini.sample.1.log");
+        let mut forwards: Vec<String> = read_lines(path).collect();
+        forwards.reverse();
+        assert_eq!(forwards, read_lines_backwards(path).collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn backwards_lines_match_lines_at_the_edges() {
+        let dir = std::env::temp_dir().join(format!("still-backwards-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for content in &["", "\n", "a", "a\n", "\na\r\nb", "a\n\nb\n", "a\r\nb\r"] {
+            let path = dir.join("edge.log");
+            fs::write(&path, content).unwrap();
+            let mut forwards: Vec<String> = read_lines(&path).collect();
+            forwards.reverse();
+            assert_eq!(forwards, read_lines_backwards(&path).collect::<Vec<String>>(), "{:?}", content);
+        }
+        let path = dir.join("latin1.log");
+        fs::write(&path, b"a\ncaf\xe9\nb\n").unwrap();
+        let lines: Vec<String> = read_lines(&path).collect();
+        assert_eq!(vec!["a", "caf\u{fffd}", "b"], lines);
+        let mut backwards: Vec<String> = read_lines_backwards(&path).collect();
+        backwards.reverse();
+        assert_eq!(lines, backwards);
+
+        // truncated halfway through, as a live log can be
+        let path = dir.join("live.log");
+        fs::write(&path, "a line of the live log\n".repeat(10_000)).unwrap();
+        let mut backwards = read_lines_backwards(&path);
+        assert!(backwards.next().is_some());
+        fs::write(&path, "").unwrap();
+        assert!(backwards.count() < 10_000);
+        assert_eq!(0, read_lines_backwards(&dir.join("gone.log")).count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use crate::ingest::{receive_datagrams, serve_clients, Store, MAX_BATCH};
+use crate::timestamp;
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+lazy_static! {
+    // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID, then structured data and the message
+    static ref RFC5424: Regex = Regex::new(r"^<([0-9]{1,3})>1 (\S+) (\S+) (\S+) (\S+) (\S+) ?(.*)$").unwrap();
+    // <PRI>Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MESSAGE
+    static ref RFC3164: Regex =
+        Regex::new(r"^<([0-9]{1,3})>([A-Z][a-z]{2} [ 0-9][0-9] [0-9]{2}:[0-9]{2}:[0-9]{2}) (\S+) ([^\s\[:]+)(?:\[([^\]]*)\])?: ?(.*)$").unwrap();
+    static ref PRI: Regex = Regex::new(r"^<([0-9]{1,3})>(.*)$").unwrap();
+    static ref SD_PARAM: Regex = Regex::new(r#"^ ([^\s=\]"]+)="((?:[^"\\\]]|\\.)*)""#).unwrap();
+}
+
+// the longest message taken over tcp, as big as a datagram can be
+const MAX_FRAME: usize = 64 * 1024;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "ntp", "security",
+    "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+pub const SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+fn priority(pri: &str, event: &mut Map<String, Value>) {
+    if let Ok(pri) = pri.parse::<usize>() {
+        if let Some(facility) = FACILITIES.get(pri / 8) {
+            event.insert("facility".to_owned(), json!(facility));
+        }
+        event.insert("severity".to_owned(), json!(SEVERITIES[pri % 8]));
+    }
+}
+
+fn nil(value: &str) -> Value {
+    if value == "-" {
+        Value::Null
+    } else {
+        json!(value)
+    }
+}
+
+// `[id name="value"...][id ...]` into {"id": {"name": "value"}} and what follows it
+fn structured_data(mut text: &str) -> (Value, &str) {
+    if text.starts_with('-') {
+        return (Value::Null, text[1..].trim_start_matches(' '));
+    }
+    let mut elements = Map::new();
+    while text.starts_with('[') {
+        let id_end = text.find(|c| c == ' ' || c == ']').unwrap_or_else(|| text.len());
+        let id = text[1..id_end].to_owned();
+        text = &text[id_end..];
+        let mut params = Map::new();
+        while let Some(cap) = SD_PARAM.captures(text) {
+            let value = cap[2].replace("\\\"", "\"").replace("\\]", "]").replace("\\\\", "\\");
+            params.insert(cap[1].to_owned(), json!(value));
+            text = &text[cap[0].len()..];
+        }
+        if !text.starts_with(']') {
+            break;
+        }
+        text = &text[1..];
+        elements.insert(id, Value::Object(params));
+    }
+    (Value::Object(elements), text.trim_start_matches(' '))
+}
+
+// bsd timestamps have no year: the one that puts it closest to now, and no offset: utc
+fn bsd_time(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    [now.year(), now.year() - 1]
+        .iter()
+        .filter_map(|year| NaiveDateTime::parse_from_str(&format!("{} {}", year, text), "%Y %b %e %H:%M:%S").ok())
+        .map(|naive| Utc.from_utc_datetime(&naive))
+        .find(|time| *time <= now + Duration::days(1))
+}
+
+// one syslog message in either format; what can't be read is kept as the message
+pub fn parse(message: &str, now: DateTime<Utc>) -> Value {
+    let message = message.trim_end_matches(|c| c == '\n' || c == '\r' || c == '\0');
+    let mut event = Map::new();
+    let mut time = None;
+    if let Some(cap) = RFC5424.captures(message) {
+        priority(&cap[1], &mut event);
+        time = timestamp::parse_rfc3339(&cap[2]);
+        event.insert("hostname".to_owned(), nil(&cap[3]));
+        event.insert("app_name".to_owned(), nil(&cap[4]));
+        event.insert("procid".to_owned(), nil(&cap[5]));
+        event.insert("msgid".to_owned(), nil(&cap[6]));
+        let (data, rest) = structured_data(&cap[7]);
+        event.insert("structured_data".to_owned(), data);
+        event.insert("message".to_owned(), json!(rest.trim_start_matches('\u{feff}')));
+    } else if let Some(cap) = RFC3164.captures(message) {
+        priority(&cap[1], &mut event);
+        time = bsd_time(&cap[2], now);
+        event.insert("hostname".to_owned(), json!(&cap[3]));
+        event.insert("app_name".to_owned(), json!(&cap[4]));
+        event.insert("procid".to_owned(), cap.get(5).map_or(Value::Null, |pid| json!(pid.as_str())));
+        event.insert("message".to_owned(), json!(&cap[6]));
+    } else if let Some(cap) = PRI.captures(message) {
+        priority(&cap[1], &mut event);
+        event.insert("message".to_owned(), json!(&cap[2]));
+    } else {
+        event.insert("message".to_owned(), json!(message));
+    }
+    event.insert("time".to_owned(), json!(timestamp::canonical(&time.unwrap_or(now))));
+    event.insert("_source".to_owned(), json!("syslog"));
+    Value::Object(event)
+}
+
+// the next message of a tcp stream: octet counted (`<length> <message>`) when it starts with a
+// digit, else up to the next newline. newlines between messages are skipped; a message longer
+// than `limit` is an error, and drops the connection
+pub fn read_frame(reader: &mut impl BufRead, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let starts_with_digit = loop {
+        match reader.fill_buf()?.first() {
+            None => return Ok(None),
+            Some(b'\n') | Some(b'\r') => reader.consume(1),
+            Some(byte) => break byte.is_ascii_digit(),
+        }
+    };
+    let too_long = || io::Error::new(io::ErrorKind::InvalidData, format!("a message is over {} bytes", limit));
+    let mut frame = vec![];
+    if starts_with_digit {
+        let mut length = vec![];
+        // as many digits as a length can have, and the space
+        reader.take(21).read_until(b' ', &mut length)?;
+        let length: usize = String::from_utf8_lossy(&length)
+            .trim_end()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad octet count"))?;
+        if length > limit {
+            return Err(too_long());
+        }
+        frame.resize(length, 0);
+        reader.read_exact(&mut frame)?;
+    } else {
+        reader.take(limit as u64 + 1).read_until(b'\n', &mut frame)?;
+        if frame.len() > limit && frame.last() != Some(&b'\n') {
+            return Err(too_long());
+        }
+    }
+    Ok(Some(frame))
+}
+
+fn message(bytes: &[u8]) -> Value {
+    parse(&String::from_utf8_lossy(bytes), Utc::now())
+}
+
+// frames are stored a batch at a time: what the client has already sent, up to `MAX_BATCH`
+fn serve(stream: TcpStream, store: &Store) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut batch = vec![];
+    loop {
+        let frame = read_frame(&mut reader, MAX_FRAME);
+        let done = !matches!(frame, Ok(Some(_)));
+        if let Ok(Some(frame)) = &frame {
+            batch.push(message(frame));
+        }
+        if !batch.is_empty() && (done || batch.len() >= MAX_BATCH || reader.buffer().is_empty()) {
+            store.append_waiting(&batch).map_err(|e| e.to_string())?;
+            batch.clear();
+        }
+        if done {
+            return frame.map(|_| ()).map_err(|e| e.to_string());
+        }
+    }
+}
+
+pub fn listen_tcp(address: &str, store: Arc<Store>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let bound = listener.local_addr()?;
+    thread::spawn(move || serve_clients(listener, store, "syslog", serve));
+    Ok(bound)
+}
+
+// a datagram is a message
+pub fn listen_udp(address: &str, store: Arc<Store>) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(address)?;
+    let bound = socket.local_addr()?;
+    receive_datagrams(socket, store, "syslog", |datagram| Ok(Some(message(datagram))));
+    Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, read_frame};
+    use crate::timestamp::parse_rfc3339;
+    use serde_json::json;
+    use std::io::BufReader;
+
+    #[test]
+    fn parses_rfc5424() {
+        let now = parse_rfc3339("2020-05-30T12:00:00Z").unwrap();
+        let message = r#"<165>1 2020-05-30T09:51:27.5Z router.lan sshd 4242 ID47 [exampleSDID@32473 iut="3" eventSource="Application \"x\""][meta seq="1"] login failed"#;
+        assert_eq!(
+            json!({
+                "facility": "local4",
+                "severity": "notice",
+                "hostname": "router.lan",
+                "app_name": "sshd",
+                "procid": "4242",
+                "msgid": "ID47",
+                "structured_data": {"exampleSDID@32473": {"iut": "3", "eventSource": "Application \"x\""}, "meta": {"seq": "1"}},
+                "message": "login failed",
+                "time": "2020-05-30T09:51:27.500000000Z",
+                "_source": "syslog",
+            }),
+            parse(message, now)
+        );
+        let nil = parse("<14>1 - - - - - - hi\n", now);
+        assert_eq!((json!(null), json!("hi")), (nil["hostname"].clone(), nil["message"].clone()));
+    }
+
+    #[test]
+    fn parses_rfc3164_with_a_guessed_year() {
+        let now = parse_rfc3339("2021-01-01T00:10:00Z").unwrap();
+        let event = parse("<34>Dec 31 23:59:01 vm1 su[123]: 'su root' failed", now);
+        assert_eq!(
+            json!({
+                "facility": "auth",
+                "severity": "crit",
+                "hostname": "vm1",
+                "app_name": "su",
+                "procid": "123",
+                "message": "'su root' failed",
+                "time": "2020-12-31T23:59:01.000000000Z",
+                "_source": "syslog",
+            }),
+            event
+        );
+        assert_eq!(json!("no header"), parse("no header", now)["message"]);
+    }
+
+    #[test]
+    fn frames_octet_counted_and_newline_messages() {
+        let mut reader = BufReader::new(&b"11 <14>1 - - -\n<14>hello\n4 a\nbc"[..]);
+        assert_eq!(b"<14>1 - - -".to_vec(), read_frame(&mut reader, 16).unwrap().unwrap());
+        assert_eq!(b"<14>hello\n".to_vec(), read_frame(&mut reader, 16).unwrap().unwrap());
+        assert_eq!(b"a\nbc".to_vec(), read_frame(&mut reader, 16).unwrap().unwrap());
+        assert_eq!(None, read_frame(&mut reader, 16).unwrap());
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit() {
+        assert!(read_frame(&mut BufReader::new(&b"17 <14>1 - - - hi"[..]), 16).is_err());
+        assert!(read_frame(&mut BufReader::new(&b"99999999999999999999999 x"[..]), 16).is_err());
+        assert!(read_frame(&mut BufReader::new(&b"<14>hello, world!\n"[..]), 16).is_err());
+        let mut reader = BufReader::new(&b"<14>hello world\n"[..]);
+        assert_eq!(b"<14>hello world\n".to_vec(), read_frame(&mut reader, 15).unwrap().unwrap());
+    }
+}
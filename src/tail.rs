@@ -0,0 +1,652 @@
+use crate::decoder;
+use crate::discovery::{Discovery, Glob};
+use crate::ingest::Store;
+use crate::kubernetes;
+use crate::meta::Sidecars;
+use crate::source::{self, LogFile, SourceOptions};
+use hyper::header::ContentType;
+use inotify::{Inotify, WatchMask};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the most read from a file in one go, so a big backlog is shipped in pieces
+const READ_LIMIT: u64 = 1024 * 1024;
+// files are looked at again this often even when inotify says nothing, as it can't see
+// through symlinks and doesn't follow dirs created after the watch
+const RESCAN: Duration = Duration::from_secs(5);
+// how long a file renamed or deleted away is still read, for what its writer had yet to write,
+// as fluentd's rotate_wait
+const ROTATE_WAIT: Duration = Duration::from_secs(5);
+// how much of the start of a file tells it apart from what replaces it after a copytruncate
+const HEAD: usize = 256;
+// the longest wait between tries while the sink keeps failing
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// where tailed events go: the local ingest store or another still's `/ingest`
+pub enum Sink {
+    Store(Arc<Store>),
+    Forward(String),
+}
+
+impl Sink {
+    fn send(&self, events: &[Value]) -> Result<(), String> {
+        match self {
+            Sink::Store(store) => store.append(events, vec![]).map(|_| ()).map_err(|e| e.to_string()),
+            Sink::Forward(url) => {
+                let body: String = events.iter().map(|event| format!("{}\n", event)).collect();
+                let mut client = hyper::Client::new();
+                client.set_read_timeout(Some(Duration::from_secs(30)));
+                let response = client
+                    .post(url.as_str())
+                    .header(ContentType::json())
+                    .body(body.as_str())
+                    .send()
+                    .map_err(|e| format!("{}: {}", url, e))?;
+                if response.status.is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} answered {}", url, response.status))
+                }
+            }
+        }
+    }
+}
+
+// a file being tailed. the handle stays open so what was written before a rename or a delete
+// can still be read
+struct Tailed {
+    file: File,
+    inode: u64,
+    offset: u64,
+    // the first bytes shipped, up to `HEAD` of them
+    head: Vec<u8>,
+    source: LogFile,
+}
+
+impl Tailed {
+    fn read_head(&mut self, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut head = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        (&mut self.file).take(length as u64).read_to_end(&mut head)?;
+        Ok(head)
+    }
+
+    // whether the file was truncated since it was last read, even if it grew back past the offset
+    fn started_over(&mut self, length: u64) -> std::io::Result<bool> {
+        if length < self.offset {
+            return Ok(true);
+        }
+        let head = self.read_head(self.head.len())?;
+        Ok(head != self.head)
+    }
+}
+
+// `/var/log/containers/*.log` as the dir to look in and what to look for under it
+fn split_pattern(pattern: &str) -> Result<(PathBuf, Discovery), String> {
+    if !pattern.starts_with('/') {
+        return Err(format!("`{}` should be an absolute path", pattern));
+    }
+    let segments: Vec<&str> = pattern.split('/').collect();
+    let literal = segments
+        .iter()
+        .position(|segment| segment.contains(|c| c == '*' || c == '?' || c == '['))
+        .unwrap_or(segments.len() - 1);
+    let root = segments[..literal].join("/");
+    let glob = Glob::parse(&segments[literal..].join("/"))?;
+    let discovery = Discovery {
+        include: vec![glob],
+        ..Discovery::default()
+    };
+    Ok((PathBuf::from(if root.is_empty() { "/" } else { &root }), discovery))
+}
+
+// the complete lines from `offset`, and how far they go; the `last` time a file is read, an
+// unfinished line at its end too
+fn read_lines(file: &mut File, offset: u64, last: bool) -> std::io::Result<(Vec<String>, u64)> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![];
+    file.take(READ_LIMIT).read_to_end(&mut bytes)?;
+    let end = match bytes.iter().rposition(|b| *b == b'\n') {
+        _ if last && (bytes.len() as u64) < READ_LIMIT => bytes.len(),
+        Some(newline) => newline + 1,
+        // a line longer than the limit is taken in pieces rather than never
+        None if bytes.len() as u64 == READ_LIMIT => bytes.len(),
+        None => 0,
+    };
+    let lines = String::from_utf8_lossy(&bytes[..end])
+        .lines()
+        .map(|line| line.trim_end_matches('\r').to_owned())
+        .collect();
+    Ok((lines, offset + end as u64))
+}
+
+// where a file was left, from the checkpoint: for files not picked up again yet
+struct Saved {
+    path: PathBuf,
+    offset: u64,
+    head: Vec<u8>,
+    // renamed or deleted away while it was still being drained
+    rotated: bool,
+}
+
+pub struct Tailer {
+    roots: Vec<(PathBuf, Discovery)>,
+    checkpoint: PathBuf,
+    sink: Sink,
+    files: HashMap<PathBuf, Tailed>,
+    // files renamed or deleted away, read until `rotate_wait` after they were
+    rotated: Vec<(Instant, Tailed)>,
+    rotate_wait: Duration,
+    // by inode
+    saved: HashMap<u64, Saved>,
+    sidecars: Sidecars,
+    // files not tailed, reported once
+    refused: HashSet<PathBuf>,
+    // scans in a row that the sink failed, to back off from it
+    sink_failures: u32,
+}
+
+impl Tailer {
+    // `patterns` are absolute globs; offsets are kept in `checkpoint` across restarts
+    pub fn new(patterns: &[String], checkpoint: PathBuf, sink: Sink) -> Result<Tailer, String> {
+        let roots = patterns.iter().map(|pattern| split_pattern(pattern)).collect::<Result<_, _>>()?;
+        let saved = match fs::read_to_string(&checkpoint) {
+            Ok(text) => {
+                let files: Map<String, Value> =
+                    serde_json::from_str(&text).map_err(|e| format!("{}: {}", checkpoint.display(), e))?;
+                files
+                    .iter()
+                    .filter_map(|(key, file)| {
+                        let head = file["head"].as_str().and_then(|head| base64::decode(head).ok()).unwrap_or_default();
+                        let saved = Saved {
+                            // older checkpoints are keyed by path
+                            path: PathBuf::from(file["path"].as_str().unwrap_or(key)),
+                            offset: file["offset"].as_u64()?,
+                            head,
+                            rotated: file["rotated"].as_bool().unwrap_or(false),
+                        };
+                        Some((file["inode"].as_u64()?, saved))
+                    })
+                    .collect()
+            }
+            Err(_) => HashMap::new(),
+        };
+        let mut tailer = Tailer {
+            roots,
+            checkpoint,
+            sink,
+            files: HashMap::new(),
+            rotated: vec![],
+            rotate_wait: ROTATE_WAIT,
+            saved,
+            sidecars: Sidecars::default(),
+            refused: HashSet::new(),
+            sink_failures: 0,
+        };
+        tailer.resume_rotated();
+        Ok(tailer)
+    }
+
+    // files that were being drained when the tailer stopped get their rotate_wait again, if
+    // they are still next to where they were tailed
+    fn resume_rotated(&mut self) {
+        let rotated: Vec<u64> = self.saved.iter().filter(|(_, saved)| saved.rotated).map(|(inode, _)| *inode).collect();
+        for inode in rotated {
+            let saved = self.saved.remove(&inode).unwrap();
+            let found = saved
+                .path
+                .parent()
+                .and_then(|dir| fs::read_dir(dir).ok())
+                .into_iter()
+                .flatten()
+                .filter_map(Result::ok)
+                .find(|entry| entry.metadata().map(|m| m.ino()).ok() == Some(inode));
+            let handle = match found.and_then(|entry| File::open(entry.path()).ok()) {
+                Some(handle) => handle,
+                None => continue,
+            };
+            let mut source = LogFile {
+                name: saved.path.to_string_lossy().into_owned(),
+                options: SourceOptions {
+                    fields: kubernetes::fields(&saved.path),
+                    ..SourceOptions::default()
+                },
+                path: saved.path.clone(),
+            };
+            self.sidecars.apply(&mut source);
+            self.saved.insert(inode, saved);
+            if let Ok(tailed) = self.open(handle, source) {
+                self.rotated.push((Instant::now(), tailed));
+            }
+        }
+    }
+
+    // starts on a file where the checkpoint left it, if it's the same file
+    fn open(&mut self, file: File, source: LogFile) -> std::io::Result<Tailed> {
+        let metadata = file.metadata()?;
+        let mut tailed = Tailed {
+            file,
+            inode: metadata.ino(),
+            offset: 0,
+            head: vec![],
+            source,
+        };
+        if let Some(saved) = self.saved.remove(&metadata.ino()) {
+            // the checkpoint only holds for the same file, not one that took its inode
+            if saved.offset <= metadata.len() && tailed.read_head(saved.head.len()).ok() == Some(saved.head.clone()) {
+                tailed.offset = saved.offset;
+                tailed.head = saved.head;
+            }
+        }
+        Ok(tailed)
+    }
+
+    // the files to tail, and the ones that mustn't be
+    fn discover(&mut self) -> (Vec<LogFile>, Vec<PathBuf>) {
+        let (mut found, mut refused) = (vec![], vec![]);
+        for (root, discovery) in &self.roots {
+            if root.is_dir() {
+                for mut file in source::log_files(root, discovery) {
+                    file.name = file.path.to_string_lossy().into_owned();
+                    self.sidecars.apply(&mut file);
+                    // lines are shipped as they are written, before the next one can say whether
+                    // it continues them
+                    if file.options.multiline.is_some() {
+                        if self.refused.insert(file.path.clone()) {
+                            eprintln!("tail: not tailing {}: its sidecar has a multiline rule, which can't be applied while tailing", file.name);
+                        }
+                        refused.push(file.path);
+                        continue;
+                    }
+                    found.push(file);
+                }
+            }
+        }
+        (found, refused)
+    }
+
+    fn ship(&mut self, path: &Path) -> Result<usize, Failure> {
+        let tailed = self.files.get_mut(path).unwrap();
+        ship(tailed, &self.sink, false)
+    }
+
+    // one look at the files: drains the ones renamed or deleted, starts on new ones and ships
+    // what was appended to all of them. offsets only move on once the events are shipped, and
+    // a file that fails doesn't hold the others up
+    pub fn scan(&mut self) -> Result<usize, String> {
+        let mut shipped = 0;
+        let mut failures = vec![];
+        let mut count = |result: Result<usize, Failure>| match result {
+            Ok(events) => shipped += events,
+            Err(failure) => failures.push(failure),
+        };
+        let now = Instant::now();
+        // a rotated file is let go once it's drained, not when its last read failed
+        let rotate_wait = self.rotate_wait;
+        for (rotated_at, tailed) in self.rotated.split_off(0) {
+            let mut tailed = tailed;
+            let last = now.saturating_duration_since(rotated_at) >= rotate_wait;
+            let result = ship(&mut tailed, &self.sink, last);
+            let drained = last && result.is_ok();
+            count(result);
+            if !drained {
+                self.rotated.push((rotated_at, tailed));
+            }
+        }
+        let (found, refused) = self.discover();
+        let inodes: HashMap<u64, &LogFile> = found
+            .iter()
+            .filter_map(|file| Some((fs::metadata(&file.path).ok()?.ino(), file)))
+            .collect();
+        let tracked: Vec<PathBuf> = self.files.keys().cloned().collect();
+        for path in tracked {
+            let inode = self.files[&path].inode;
+            if refused.contains(&path) {
+                // picked up where it was left if the rule goes away
+                let tailed = self.files.remove(&path).unwrap();
+                let saved = Saved {
+                    path,
+                    offset: tailed.offset,
+                    head: tailed.head,
+                    rotated: false,
+                };
+                self.saved.insert(inode, saved);
+                continue;
+            }
+            if fs::metadata(&path).map(|m| m.ino()).ok() == Some(inode) {
+                continue;
+            }
+            count(self.ship(&path));
+            let mut tailed = self.files.remove(&path).unwrap();
+            // renamed to a path that is tailed too: carry on there
+            match inodes.get(&inode) {
+                Some(file) => {
+                    tailed.source = (*file).clone();
+                    self.files.insert(file.path.clone(), tailed);
+                }
+                None => self.rotated.push((now, tailed)),
+            }
+        }
+        for file in &found {
+            if self.files.contains_key(&file.path) {
+                continue;
+            }
+            let handle = match File::open(&file.path) {
+                Ok(handle) => handle,
+                Err(_) => continue,
+            };
+            match self.open(handle, file.clone()) {
+                Ok(tailed) => {
+                    self.files.insert(file.path.clone(), tailed);
+                }
+                Err(e) => count(Err(Failure::File(format!("{}: {}", file.path.display(), e)))),
+            }
+        }
+        let paths: Vec<PathBuf> = self.files.keys().cloned().collect();
+        for path in paths {
+            count(self.ship(&path));
+        }
+        let saved = self.save();
+        if failures.iter().any(|failure| matches!(failure, Failure::Sink(_))) {
+            self.sink_failures += 1;
+        } else {
+            self.sink_failures = 0;
+        }
+        let mut errors: Vec<String> = failures.into_iter().map(Failure::into_message).collect();
+        errors.extend(saved.err());
+        if errors.is_empty() {
+            Ok(shipped)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    // how long to leave the sink be before the next scan, longer each time it failed in a row
+    fn backoff(&self) -> Option<Duration> {
+        match self.sink_failures {
+            0 => None,
+            failures => Some(MAX_BACKOFF.min(Duration::from_secs(1) * 2u32.pow((failures - 1).min(16)))),
+        }
+    }
+
+    // the files being tailed and drained, and the ones from the last checkpoint not seen since
+    fn save(&self) -> Result<(), String> {
+        let mut files = Map::new();
+        let mut add = |inode: u64, path: &Path, offset: u64, head: &[u8], rotated: bool| {
+            let file = json!({
+                "path": path.to_string_lossy(),
+                "inode": inode,
+                "offset": offset,
+                "head": base64::encode(head),
+                "rotated": rotated,
+            });
+            files.insert(inode.to_string(), file);
+        };
+        for (inode, saved) in &self.saved {
+            add(*inode, &saved.path, saved.offset, &saved.head, saved.rotated);
+        }
+        for (_, tailed) in &self.rotated {
+            add(tailed.inode, &tailed.source.path, tailed.offset, &tailed.head, true);
+        }
+        for tailed in self.files.values() {
+            add(tailed.inode, &tailed.source.path, tailed.offset, &tailed.head, false);
+        }
+        let partial = self.checkpoint.with_extension("partial");
+        fs::write(&partial, Value::Object(files).to_string())
+            .and_then(|_| fs::rename(&partial, &self.checkpoint))
+            .map_err(|e| format!("{}: {}", self.checkpoint.display(), e))
+    }
+
+    // the dirs whose changes should wake the tailer: the roots and where the files really are
+    fn watched(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self.roots.iter().map(|(root, _)| root.clone()).collect();
+        for path in self.files.keys() {
+            if let Some(parent) = fs::canonicalize(path).ok().as_ref().and_then(|path| path.parent()) {
+                dirs.push(parent.to_path_buf());
+            }
+        }
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+}
+
+// why a file couldn't be shipped: reading it, or sending what was read
+enum Failure {
+    File(String),
+    Sink(String),
+}
+
+impl Failure {
+    fn into_message(self) -> String {
+        match self {
+            Failure::File(message) | Failure::Sink(message) => message,
+        }
+    }
+}
+
+// ships what was written to a file since the last time, all of it the `last` time
+fn ship(tailed: &mut Tailed, sink: &Sink, last: bool) -> Result<usize, Failure> {
+    let path = tailed.source.path.clone();
+    let error = |e: std::io::Error| Failure::File(format!("{}: {}", path.display(), e));
+    let length = tailed.file.metadata().map_err(error)?.len();
+    if tailed.started_over(length).map_err(error)? {
+        // copytruncate: the file started over
+        tailed.offset = 0;
+        tailed.head.clear();
+    }
+    let mut shipped = 0;
+    while tailed.offset < length {
+        let (lines, end) = read_lines(&mut tailed.file, tailed.offset, last).map_err(error)?;
+        if end == tailed.offset {
+            break;
+        }
+        let source = &tailed.source;
+        let events: Vec<Value> = decoder::decode(Box::new(lines.into_iter()), source.options.format, false)
+            .filter_map(|line| source::to_event(&line, source))
+            .collect();
+        if !events.is_empty() {
+            sink.send(&events).map_err(Failure::Sink)?;
+        }
+        shipped += events.len();
+        tailed.offset = end;
+        if tailed.head.len() < HEAD {
+            tailed.head = tailed.read_head(HEAD.min(end as usize)).map_err(error)?;
+        }
+    }
+    Ok(shipped)
+}
+
+// tails in the background: a scan whenever inotify reports a change, and every `RESCAN` anyway
+pub fn run(mut tailer: Tailer) -> std::io::Result<()> {
+    let mut inotify = Inotify::init()?;
+    let mask = WatchMask::MODIFY | WatchMask::CREATE | WatchMask::MOVE | WatchMask::DELETE | WatchMask::CLOSE_WRITE;
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            if let Err(e) = tailer.scan() {
+                eprintln!("tail: {}", e);
+            }
+            for dir in tailer.watched() {
+                // gone dirs just aren't watched until they're back
+                let _ = inotify.add_watch(&dir, mask);
+            }
+            // what the sink refused is tried again, once it had some time
+            if let Some(backoff) = tailer.backoff() {
+                thread::sleep(backoff);
+                continue;
+            }
+            let scanned = Instant::now();
+            while scanned.elapsed() < RESCAN {
+                match inotify.read_events(&mut buffer).map(|mut events| events.next().is_some()) {
+                    Ok(true) => break,
+                    Ok(false) => thread::sleep(Duration::from_millis(100)),
+                    Err(e) => {
+                        eprintln!("tail: {}", e);
+                        thread::sleep(RESCAN);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_pattern, Sink, Tailer};
+    use crate::ingest::Store;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn append(path: &Path, numbers: &[u32]) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        for n in numbers {
+            writeln!(file, "{{\"time\": \"2020-05-30T09:51:27Z\", \"n\": {}}}", n).unwrap();
+        }
+    }
+
+    fn stored(dir: &Path) -> Vec<u64> {
+        fs::read_to_string(dir.join("2020-05-30.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["n"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn splits_patterns_at_the_first_wildcard() {
+        let (root, discovery) = split_pattern("/var/log/containers/*.log").unwrap();
+        assert_eq!((PathBuf::from("/var/log/containers"), 1), (root, discovery.include.len()));
+        assert_eq!(PathBuf::from("/var/log"), split_pattern("/var/log/syslog").unwrap().0);
+        assert!(split_pattern("logs/*.log").is_err());
+    }
+
+    #[test]
+    fn follows_appends_truncation_and_rotation() {
+        let dir = std::env::temp_dir().join(format!("still-tail-{}", std::process::id()));
+        let (logs, ingest) = (dir.join("logs"), dir.join("ingest"));
+        fs::create_dir_all(&logs).unwrap();
+        let log = logs.join("app.log");
+        let pattern = format!("{}/*.log", logs.display());
+        let checkpoint = dir.join("tail.checkpoint");
+        let tailer = || Tailer::new(&[pattern.clone()], checkpoint.clone(), Sink::Store(Arc::new(Store::new(ingest.clone())))).unwrap();
+        let mut tail = tailer();
+
+        append(&log, &[1, 2]);
+        assert_eq!(2, tail.scan().unwrap());
+        append(&log, &[3]);
+        fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"{\"n\": ").unwrap();
+        assert_eq!(1, tail.scan().unwrap());
+
+        // copytruncate
+        fs::write(&log, "").unwrap();
+        append(&log, &[4]);
+        assert_eq!(1, tail.scan().unwrap());
+
+        // rename, with a line written just before it
+        append(&log, &[5]);
+        fs::rename(&log, logs.join("app.log.1")).unwrap();
+        append(&log, &[6]);
+        assert_eq!(2, tail.scan().unwrap());
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], stored(&ingest));
+
+        // a restart carries on from the checkpoint
+        append(&log, &[7]);
+        assert_eq!(1, tailer().scan().unwrap());
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], stored(&ingest));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notices_truncation_even_past_the_old_offset() {
+        let dir = std::env::temp_dir().join(format!("still-tail-truncate-{}", std::process::id()));
+        let (logs, ingest) = (dir.join("logs"), dir.join("ingest"));
+        fs::create_dir_all(&logs).unwrap();
+        let log = logs.join("app.log");
+        let pattern = format!("{}/*.log", logs.display());
+        let mut tail = Tailer::new(&[pattern], dir.join("tail.checkpoint"), Sink::Store(Arc::new(Store::new(ingest.clone())))).unwrap();
+
+        append(&log, &[1, 2]);
+        assert_eq!(2, tail.scan().unwrap());
+        fs::write(&log, "").unwrap();
+        append(&log, &[3, 4, 5]);
+        assert_eq!(3, tail.scan().unwrap());
+        assert_eq!(vec![1, 2, 3, 4, 5], stored(&ingest));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drains_rotated_files_for_a_while() {
+        let dir = std::env::temp_dir().join(format!("still-tail-rotate-{}", std::process::id()));
+        let (logs, ingest) = (dir.join("logs"), dir.join("ingest"));
+        fs::create_dir_all(&logs).unwrap();
+        let log = logs.join("app.log");
+        let pattern = format!("{}/*.log", logs.display());
+        let mut tail = Tailer::new(&[pattern.clone()], dir.join("tail.checkpoint"), Sink::Store(Arc::new(Store::new(ingest.clone())))).unwrap();
+
+        append(&log, &[1]);
+        assert_eq!(1, tail.scan().unwrap());
+        let rotated = logs.join("app.log.1");
+        fs::rename(&log, &rotated).unwrap();
+        assert_eq!(0, tail.scan().unwrap());
+        // the writer still has the old file open
+        append(&rotated, &[2]);
+        fs::OpenOptions::new().append(true).open(&rotated).unwrap().write_all(b"{\"time\": \"2020-05-30T09:51:27Z\", \"n\": 3}").unwrap();
+        assert_eq!(1, tail.scan().unwrap());
+        // a restart meanwhile drains it all the same
+        let mut tail = Tailer::new(&[pattern], dir.join("tail.checkpoint"), Sink::Store(Arc::new(Store::new(ingest.clone())))).unwrap();
+        tail.rotate_wait = Duration::from_secs(0);
+        assert_eq!(1, tail.scan().unwrap());
+        assert!(tail.rotated.is_empty());
+        assert_eq!(vec![1, 2, 3], stored(&ingest));
+
+        // multiline rules can't be honoured while tailing
+        fs::write(logs.join("app.log.meta"), "multiline = \"indented\"").unwrap();
+        append(&log, &[4]);
+        assert_eq!(0, tail.scan().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stops_on_multiline_rules_and_backs_off_a_failing_sink() {
+        let dir = std::env::temp_dir().join(format!("still-tail-sink-{}", std::process::id()));
+        let (logs, ingest) = (dir.join("logs"), dir.join("ingest"));
+        fs::create_dir_all(&logs).unwrap();
+        let (log, other) = (logs.join("app.log"), logs.join("other.log"));
+        let pattern = format!("{}/*.log", logs.display());
+        let checkpoint = dir.join("tail.checkpoint");
+        let mut tail = Tailer::new(&[pattern.clone()], checkpoint.clone(), Sink::Store(Arc::new(Store::new(ingest.clone())))).unwrap();
+        append(&log, &[1]);
+        assert_eq!(1, tail.scan().unwrap());
+        // a file already tailed stops once its sidecar has a multiline rule
+        fs::write(logs.join("app.log.meta"), "multiline = \"indented\"").unwrap();
+        append(&log, &[2]);
+        assert_eq!(0, tail.scan().unwrap());
+        assert!(tail.files.is_empty());
+
+        // nothing listens there
+        let mut tail = Tailer::new(&[pattern], checkpoint.clone(), Sink::Forward("http://127.0.0.1:1/ingest".to_owned())).unwrap();
+        append(&other, &[3]);
+        assert!(tail.scan().is_err());
+        assert!(tail.scan().is_err());
+        assert_eq!(Some(Duration::from_secs(2)), tail.backoff());
+        // what is known of each file is still saved, app.log's too though it isn't tailed
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&checkpoint).unwrap()).unwrap();
+        assert_eq!(2, saved.as_object().unwrap().len());
+        assert_eq!(vec![1], stored(&ingest));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde_json::Value;
+
+lazy_static! {
+    static ref NGINX: Regex = Regex::new(r"\[?(\d{2}/[A-Za-z]{3}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4})\]?").unwrap();
+    static ref LOGFMT: Regex = Regex::new(r#"(?:^|\s)(?:time|ts|timestamp)=(?:"([^"]*)"|(\S+))"#).unwrap();
+}
+
+// timestamps are stored as RFC3339 in UTC with a fixed nanosecond precision,
+// so comparing or sorting them as strings is the same as doing it by time
+pub fn canonical(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+pub fn parse_rfc3339(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+pub fn from_epoch_seconds(seconds: f64) -> Option<DateTime<Utc>> {
+    if !seconds.is_finite() {
+        return None;
+    }
+    let whole = seconds.floor();
+    let nanos = ((seconds - whole) * 1e9).round().min(999_999_999.0) as u32;
+    Utc.timestamp_opt(whole as i64, nanos).single()
+}
+
+// epoch numbers without a stated unit are only taken as times when, read as seconds, millis,
+// micros or nanos, they land between 2000 and 2100: a status code or a byte count isn't a time
+const PLAUSIBLE_SECONDS: std::ops::Range<f64> = 946_684_800.0..4_102_444_800.0;
+
+fn from_epoch_guessing_unit(number: f64) -> Option<DateTime<Utc>> {
+    [1.0, 1e3, 1e6, 1e9]
+        .iter()
+        .map(|unit| number / unit)
+        .find(|seconds| PLAUSIBLE_SECONDS.contains(seconds))
+        .and_then(from_epoch_seconds)
+}
+
+// `2020-05-30 09:51:27.5`, with a T or a space and without an offset
+fn parse_naive_iso(text: &str, zone: &Zone) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()
+        .and_then(|naive| zone.localize(&naive))
+}
+
+// the `[30/May/2020:09:51:27 +0000]` of nginx/apache access logs, found anywhere in the text
+fn parse_nginx(text: &str) -> Option<DateTime<Utc>> {
+    NGINX
+        .captures(text)
+        .and_then(|cap| DateTime::parse_from_str(&cap[1], "%d/%b/%Y:%H:%M:%S %z").ok())
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// the `time=` key of a logfmt line, quoted or not
+fn parse_logfmt(text: &str) -> Option<DateTime<Utc>> {
+    LOGFMT
+        .captures(text)
+        .and_then(|cap| cap.get(1).or_else(|| cap.get(2)))
+        .and_then(|m| parse_rfc3339(m.as_str()))
+}
+
+// a timezone as written in a query or config: an IANA name, "UTC" or a fixed "+02:00"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    pub fn parse(name: &str) -> Option<Zone> {
+        let name = name.trim();
+        if name.starts_with('+') || name.starts_with('-') {
+            let sign = if name.starts_with('-') { -1 } else { 1 };
+            let digits: String = name[1..].chars().filter(|c| *c != ':').collect();
+            if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let hours: i32 = digits[..2].parse().ok()?;
+            let minutes: i32 = digits[2..].parse().ok()?;
+            FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(Zone::Fixed)
+        } else {
+            name.parse::<Tz>().ok().map(Zone::Named)
+        }
+    }
+
+    // a wall-clock time in this zone; the earlier one when DST makes it ambiguous
+    pub fn localize(&self, naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Named(tz) => tz.from_local_datetime(naive).earliest().map(|t| t.with_timezone(&Utc)),
+            Zone::Fixed(offset) => offset.from_local_datetime(naive).earliest().map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    pub fn format(&self, time: &DateTime<Utc>, format: &str) -> String {
+        match self {
+            Zone::Named(tz) => time.with_timezone(tz).format(format).to_string(),
+            Zone::Fixed(offset) => time.with_timezone(offset).format(format).to_string(),
+        }
+    }
+
+    pub fn to_rfc3339(&self, time: &DateTime<Utc>) -> String {
+        match self {
+            Zone::Named(tz) => time.with_timezone(tz).to_rfc3339_opts(SecondsFormat::Nanos, false),
+            Zone::Fixed(offset) => time.with_timezone(offset).to_rfc3339_opts(SecondsFormat::Nanos, false),
+        }
+    }
+}
+
+impl Default for Zone {
+    fn default() -> Zone {
+        Zone::Named(Tz::UTC)
+    }
+}
+
+pub fn is_valid_format(format: &str) -> bool {
+    StrftimeItems::new(format).all(|item| item != Item::Error)
+}
+
+// `format` is one of the named formats (rfc3339, nginx, logfmt, epoch, epoch_millis) or a strftime
+// pattern; without one every named format is tried in turn, and iso times without an offset.
+// times that don't carry an offset are read in `zone`
+pub fn parse(value: &Value, format: Option<&str>, zone: &Zone) -> Option<DateTime<Utc>> {
+    let text = match value {
+        Value::String(text) => text.as_str(),
+        Value::Number(number) => {
+            let number = number.as_f64()?;
+            return match format {
+                None => from_epoch_guessing_unit(number),
+                Some("epoch") => from_epoch_seconds(number),
+                Some("epoch_millis") => from_epoch_seconds(number / 1e3),
+                Some(_) => None,
+            };
+        }
+        _ => return None,
+    };
+    match format {
+        None => parse_rfc3339(text)
+            .or_else(|| text.trim().parse().ok().and_then(from_epoch_guessing_unit))
+            .or_else(|| parse_naive_iso(text, zone))
+            .or_else(|| parse_nginx(text))
+            .or_else(|| parse_logfmt(text)),
+        Some("rfc3339") => parse_rfc3339(text),
+        Some("nginx") => parse_nginx(text),
+        Some("logfmt") => parse_logfmt(text),
+        Some("epoch") => text.trim().parse().ok().and_then(from_epoch_seconds),
+        Some("epoch_millis") => text.trim().parse::<f64>().ok().and_then(|m| from_epoch_seconds(m / 1e3)),
+        Some(pattern) => DateTime::parse_from_str(text.trim(), pattern)
+            .map(|time| time.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(text.trim(), pattern)
+                    .ok()
+                    .and_then(|naive| zone.localize(&naive))
+            }),
+    }
+}
+
+pub fn is_named_format(format: &str) -> bool {
+    ["rfc3339", "nginx", "logfmt", "epoch", "epoch_millis"].contains(&format)
+}
+
+// when an event happened, from the first of the usual time fields that holds a readable time
+pub fn event_time(event: &Value) -> Option<DateTime<Utc>> {
+    event_time_in(event, &Zone::default())
+}
+
+pub fn event_time_in(event: &Value, zone: &Zone) -> Option<DateTime<Utc>> {
+    // `date` is where fluent-bit's http output puts it
+    ["_time", "@timestamp", "timestamp", "time", "ts", "date"]
+        .iter()
+        .filter_map(|field| event.get(field))
+        .find_map(|value| parse(value, None, zone))
+}
+
+// time_diff: a - b in seconds
+pub fn diff_seconds(a: &DateTime<Utc>, b: &DateTime<Utc>) -> f64 {
+    let diff = a.signed_duration_since(*b);
+    match diff.num_nanoseconds() {
+        Some(nanos) => nanos as f64 / 1e9,
+        None => diff.num_milliseconds() as f64 / 1e3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical, diff_seconds, event_time, from_epoch_seconds, parse, parse_rfc3339, Zone};
+    use serde_json::json;
+
+    fn parsed(value: serde_json::Value, format: Option<&str>) -> Option<String> {
+        parse(&value, format, &Zone::default()).map(|time| canonical(&time))
+    }
+
+    #[test]
+    fn canonical_form_is_utc_with_nanoseconds() {
+        let time = parse_rfc3339("2020-05-30T10:51:27.5+01:00").unwrap();
+        assert_eq!("2020-05-30T09:51:27.500000000Z", canonical(&time));
+    }
+
+    #[test]
+    fn reads_epoch_seconds() {
+        assert_eq!(
+            "2020-05-30T09:51:27.250000000Z",
+            canonical(&from_epoch_seconds(1_590_832_287.25).unwrap())
+        );
+    }
+
+    #[test]
+    fn detects_the_formats_in_the_fixtures() {
+        let expected = Some("2020-05-30T09:51:27.000000000Z".to_owned());
+        assert_eq!(expected, parsed(json!("2020-05-30T09:51:27Z"), None));
+        assert_eq!(
+            expected,
+            parsed(json!(r#"10.244.0.70 - - [30/May/2020:09:51:27 +0000] "GET /icons/seventh_son.png HTTP/1.1" 200"#), None)
+        );
+        assert_eq!(
+            expected,
+            parsed(json!(r#"time="2020-05-30T09:51:27Z" level=info msg="node get capabilities called""#), None)
+        );
+        assert_eq!(expected, parsed(json!(1_590_832_287), None));
+        assert_eq!(expected, parsed(json!(1_590_832_287_000u64), None));
+        assert_eq!(expected, parsed(json!("1590832287000"), Some("epoch_millis")));
+    }
+
+    #[test]
+    fn guesses_only_plausible_epochs() {
+        let expected = Some("2020-05-30T09:51:27.000000000Z".to_owned());
+        assert_eq!(expected, parsed(json!(1_590_832_287_000_000u64), None));
+        assert_eq!(expected, parsed(json!("1590832287000000000"), None));
+        assert_eq!(None, parsed(json!(200), None));
+        assert_eq!(None, parsed(json!("404"), None));
+        assert_eq!(None, parsed(json!(-1_590_832_287), None));
+        assert_eq!(None, parsed(json!(99_999_999_999_999u64), None));
+        assert_eq!(Some("1970-01-01T00:03:20.000000000Z".to_owned()), parsed(json!(200), Some("epoch")));
+    }
+
+    #[test]
+    fn naive_patterns_are_read_in_the_zone() {
+        let rome = Zone::parse("Europe/Rome").unwrap();
+        let time = parse(&json!("30/05/2020 11:51:27"), Some("%d/%m/%Y %H:%M:%S"), &rome).unwrap();
+        assert_eq!("2020-05-30T09:51:27.000000000Z", canonical(&time));
+        assert_eq!("2020-05-30T11:51:27.000000000+02:00", rome.to_rfc3339(&time));
+        assert_eq!("09:51", Zone::parse("+00:00").unwrap().format(&time, "%H:%M"));
+        assert_eq!("07:51", Zone::parse("-02:00").unwrap().format(&time, "%H:%M"));
+        let time = parse(&json!("2020-05-30 11:51:27.5"), None, &rome).unwrap();
+        assert_eq!("2020-05-30T09:51:27.500000000Z", canonical(&time));
+    }
+
+    #[test]
+    fn event_time_prefers_the_normalised_field() {
+        let event = json!({"_time": "2020-05-30T09:51:27Z", "time": "2020-05-30T00:00:00Z"});
+        assert_eq!("2020-05-30T09:51:27.000000000Z", canonical(&event_time(&event).unwrap()));
+        assert_eq!(None, event_time(&json!({"time": "yesterday", "log": "2020-05-30T09:51:27Z"})));
+    }
+
+    #[test]
+    fn diffs_in_seconds() {
+        let a = parse_rfc3339("2020-05-30T09:51:27.5Z").unwrap();
+        let b = parse_rfc3339("2020-05-30T09:50:27Z").unwrap();
+        assert!((diff_seconds(&a, &b) - 60.5).abs() < 1e-9);
+    }
+}
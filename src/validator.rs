@@ -0,0 +1,241 @@
+use crate::ast::*;
+use crate::field_path::FieldPath;
+use crate::query_error::QueryError;
+use crate::timestamp;
+use crate::timestamp::Zone;
+use crate::visitor::Visitor;
+use regex::Regex;
+use std::collections::HashSet;
+
+// catches what would otherwise blow up (or silently match nothing) halfway through a search
+pub struct Validator<'ast> {
+    query: &'ast str,
+    pub errors: Vec<QueryError>,
+    // None until an aggregation replaces the events, after which only these fields exist
+    known_fields: Option<HashSet<FieldPath<'ast>>>,
+}
+
+impl<'ast> Validator<'ast> {
+    pub fn new(query: &'ast str) -> Validator<'ast> {
+        Validator {
+            query,
+            errors: vec![],
+            known_fields: None,
+        }
+    }
+
+    fn error(&mut self, span: (usize, usize), kind: &'static str, message: String) {
+        self.errors.push(QueryError::at(self.query, span, kind, message));
+    }
+
+    fn check_field_exists(&mut self, field: &'ast FieldPath<'ast>, stage: &str) {
+        let missing = match &self.known_fields {
+            Some(known) => !known.contains(field),
+            None => false,
+        };
+        if missing {
+            let mut known: Vec<String> = self.known_fields.iter().flatten().map(|f| f.to_string()).collect();
+            known.sort();
+            self.error(
+                field.span,
+                "unknown_field",
+                format!("{} on {:?}, but only {:?} exist after the aggregation", stage, field.to_string(), known),
+            );
+        }
+    }
+
+    // a field written by a stage exists from then on, aggregation or not
+    fn learn_field(&mut self, field: FieldPath<'ast>) {
+        if let Some(known) = &mut self.known_fields {
+            known.insert(field);
+        }
+    }
+
+    fn check_zone(&mut self, zone: &'ast Literal<'ast>) {
+        if Zone::parse(zone).is_none() {
+            self.error(zone.span, "unknown_timezone", format!("{:?} is not a timezone", zone));
+        }
+    }
+
+    fn compile(&mut self, pattern: &'ast Literal<'ast>) -> Option<Regex> {
+        match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                self.error(pattern.span, "invalid_regex", e.to_string());
+                None
+            }
+        }
+    }
+}
+
+impl<'ast> Visitor<'ast> for Validator<'ast> {
+    fn visit_search(&mut self, _search: &'ast Search<'ast>) {}
+    fn visit_search_term(&mut self, _search_term: &'ast SearchTerm<'ast>) {}
+    fn visit_transform(&mut self, transform: &'ast Transform<'ast>) {
+        match transform {
+            Transform::Filter {
+                field,
+                comparison,
+                value,
+            } => {
+                self.check_field_exists(field, "where");
+                if let Comparison::Match = comparison {
+                    self.compile(value);
+                }
+            }
+            Transform::Parse {
+                field,
+                parser,
+                bindings,
+            } => {
+                self.check_field_exists(field, "parse");
+                if let Some(regex) = self.compile(parser) {
+                    let groups = regex.captures_len() - 1;
+                    if groups != bindings.len() {
+                        self.error(
+                            parser.span,
+                            "binding_count_mismatch",
+                            format!("{} capture groups but {} bindings", groups, bindings.len()),
+                        );
+                    }
+                }
+                let mut seen = HashSet::new();
+                for binding in bindings {
+                    if !seen.insert(&binding.field) {
+                        self.error(
+                            binding.field.span,
+                            "duplicate_binding",
+                            format!("{:?} is bound more than once", binding.field.to_string()),
+                        );
+                    }
+                }
+                if let Some(known) = &mut self.known_fields {
+                    known.extend(bindings.iter().map(|binding| binding.field.clone()));
+                }
+            }
+            Transform::Cast { field, to: _ } => self.check_field_exists(field, "cast"),
+            Transform::ParseTime { field, format, target } => {
+                self.check_field_exists(field, "parse_time");
+                if let Some(format) = format {
+                    if !timestamp::is_named_format(format) && !timestamp::is_valid_format(format) {
+                        self.error(format.span, "invalid_time_format", format!("{:?} is not a time format", format));
+                    }
+                }
+                self.learn_field(target.clone().unwrap_or_else(|| FieldPath::parse("_time", 0).unwrap()));
+            }
+            Transform::FormatTime { field, format, zone, target } => {
+                self.check_field_exists(field, "format_time");
+                if !timestamp::is_valid_format(format) {
+                    self.error(format.span, "invalid_time_format", format!("{:?} is not a time format", format));
+                }
+                if let Some(zone) = zone {
+                    self.check_zone(zone);
+                }
+                self.learn_field(target.clone().unwrap_or_else(|| field.clone()));
+            }
+            Transform::TimeDiff { from, to, target } => {
+                self.check_field_exists(from, "time_diff");
+                self.check_field_exists(to, "time_diff");
+                self.learn_field(target.clone());
+            }
+            Transform::ConvertTz { field, zone, target } => {
+                self.check_field_exists(field, "convert_tz");
+                self.check_zone(zone);
+                self.learn_field(target.clone().unwrap_or_else(|| field.clone()));
+            }
+            _ => {}
+        }
+    }
+    fn visit_aggregation(&mut self, aggregation: &'ast Aggregation<'ast>) {
+        match aggregation {
+            Aggregation::Count(fields) => {
+                for field in fields {
+                    self.check_field_exists(field, "count by");
+                }
+                let mut known: HashSet<FieldPath<'ast>> = fields.iter().cloned().collect();
+                known.insert(FieldPath::parse("_count", 0).unwrap());
+                self.known_fields = Some(known);
+            }
+        }
+    }
+    fn visit_sort(&mut self, sort: &'ast Sort<'ast>) {
+        let fields = match sort {
+            Sort::Asc(fields) => fields,
+            Sort::Desc(fields) => fields,
+        };
+        for field in fields {
+            self.check_field_exists(field, "sort by");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Validator;
+    use crate::ast::Search;
+    use crate::search::SearchParser;
+    use crate::visitor::Visitable;
+
+    fn validate(query: &str) -> Vec<(&'static str, usize)> {
+        let search: Search = *SearchParser::new().parse(query).unwrap();
+        let mut validator = Validator::new(query);
+        search.accept(&mut validator);
+        validator.errors.iter().map(|e| (e.kind, e.offset)).collect()
+    }
+
+    #[test]
+    fn sketch_query_is_valid() {
+        assert_eq!(
+            Vec::<(&str, usize)>::new(),
+            validate(
+                r#"protocol.kitchen
+                | parse log with '"([^ ]+) ([^ ]+) HTTP/1.1" ([\d]{3})' as verb, path, response_code
+                | where response_code = "200"
+                | count by verb, path
+                | sort by _count"#
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert_eq!(vec![("invalid_regex", 21)], validate(r#"* | where log match "GET (""#));
+        assert_eq!(vec![("invalid_regex", 20)], validate(r#"* | parse log with '(['  as a"#));
+        // unescaped literals point at where they were written too
+        assert_eq!(vec![("invalid_regex", 21)], validate(r#"* | where log match "\t(""#));
+        assert_eq!(vec![("invalid_regex", 20)], validate(r#"* | parse log with '\'(['  as a"#));
+    }
+
+    #[test]
+    fn rejects_bindings_not_matching_capture_groups() {
+        assert_eq!(
+            vec![("binding_count_mismatch", 20)],
+            validate(r#"* | parse log with '(\w+) (\w+)' as verb"#)
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_bindings() {
+        assert_eq!(
+            vec![("duplicate_binding", 39)],
+            validate(r#"* | parse log with '(\w+) (\w+)' as a, a"#)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_time_formats_and_zones() {
+        assert_eq!(
+            vec![("invalid_time_format", 24), ("unknown_timezone", 30)],
+            validate(r#"* | format_time(_time, "%Q", "Mars/Olympus")"#)
+        );
+        assert_eq!(Vec::<(&str, usize)>::new(), validate(r#"* | parse_time(log, "nginx") | convert_tz(_time, "+02:00")"#));
+    }
+
+    #[test]
+    fn rejects_fields_lost_by_count() {
+        assert_eq!(
+            vec![("unknown_field", 26), ("unknown_field", 54)],
+            validate(r#"* | count by verb | where log = "x" | sort by _count, path"#)
+        );
+    }
+}
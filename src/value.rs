@@ -0,0 +1,134 @@
+use crate::ast::ValueType;
+use crate::timestamp;
+use crate::timestamp::Zone;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+// how a field reads when it's compared to or matched against a query literal:
+// strings as themselves, anything else as its json (`200`, `true`, `{"a":1}`), nulls included.
+// a missing field has no text at all, so `=` and `match` never hold for it and `!=` always does
+pub fn text(value: &Value) -> Cow<str> {
+    match value {
+        Value::String(string) => Cow::Borrowed(string),
+        other => Cow::Owned(other.to_string()),
+    }
+}
+
+// total order used by `sort`: missing < null < bools < numbers < strings < arrays < objects,
+// numbers by value, strings lexicographically, arrays and objects by their json
+pub fn compare(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(Value::Number(a)), Some(Value::Number(b))) => {
+            match (a.as_i64(), b.as_i64()) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                _ => a
+                    .as_f64()
+                    .partial_cmp(&b.as_f64())
+                    .unwrap_or(Ordering::Equal),
+            }
+        }
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (Some(a @ Value::Array(_)), Some(b @ Value::Array(_)))
+        | (Some(a @ Value::Object(_)), Some(b @ Value::Object(_))) => a.to_string().cmp(&b.to_string()),
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+// `<`, `>` and friends: numerically when both sides are numbers, by text otherwise
+pub fn compare_literal(value: &Value, literal: &str) -> Ordering {
+    match (value.as_f64(), literal.trim().parse::<f64>()) {
+        (Some(number), Ok(literal)) => number.partial_cmp(&literal).unwrap_or(Ordering::Equal),
+        _ => text(value).as_ref().cmp(literal),
+    }
+}
+
+// None when the value can't be read as that type; callers store that as null
+pub fn cast(value: &Value, to: ValueType) -> Option<Value> {
+    match to {
+        ValueType::Int => match value {
+            Value::Number(number) => number
+                .as_i64()
+                .or_else(|| number.as_f64().map(|f| f.trunc() as i64))
+                .map(|i| json!(i)),
+            Value::String(string) => string.trim().parse::<i64>().ok().map(|i| json!(i)),
+            Value::Bool(b) => Some(json!(*b as i64)),
+            _ => None,
+        },
+        ValueType::Float => match value {
+            Value::Number(number) => number.as_f64().map(|f| json!(f)),
+            Value::String(string) => string
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(|f| json!(f)),
+            _ => None,
+        },
+        ValueType::Bool => match value {
+            Value::Bool(b) => Some(json!(*b)),
+            Value::Number(number) => number.as_f64().map(|f| json!(f != 0.0)),
+            Value::String(string) => match string.trim().to_lowercase().as_str() {
+                "true" | "yes" | "1" => Some(json!(true)),
+                "false" | "no" | "0" => Some(json!(false)),
+                _ => None,
+            },
+            _ => None,
+        },
+        ValueType::Timestamp => timestamp::parse(value, None, &Zone::default()).map(|time| json!(timestamp::canonical(&time))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cast, compare, compare_literal, text};
+    use crate::ast::ValueType;
+    use serde_json::json;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn text_of_non_strings_is_their_json() {
+        assert_eq!("GET", text(&json!("GET")));
+        assert_eq!("200", text(&json!(200)));
+        assert_eq!("null", text(&json!(null)));
+        assert_eq!(r#"{"a":1}"#, text(&json!({"a": 1})));
+    }
+
+    #[test]
+    fn compares_numbers_by_value_and_missing_first() {
+        assert_eq!(Ordering::Less, compare(Some(&json!(9)), Some(&json!(10))));
+        assert_eq!(Ordering::Greater, compare(Some(&json!(1.5)), Some(&json!(1))));
+        assert_eq!(Ordering::Less, compare(None, Some(&json!(null))));
+        assert_eq!(Ordering::Less, compare(Some(&json!(10)), Some(&json!("9"))));
+    }
+
+    #[test]
+    fn compares_numbers_to_literals_numerically() {
+        assert_eq!(Ordering::Greater, compare_literal(&json!(404), "99"));
+        assert_eq!(Ordering::Less, compare_literal(&json!("404"), "99"));
+    }
+
+    #[test]
+    fn casts_strings_to_types() {
+        assert_eq!(Some(json!(200)), cast(&json!("200"), ValueType::Int));
+        assert_eq!(Some(json!(0.25)), cast(&json!("0.25"), ValueType::Float));
+        assert_eq!(Some(json!(true)), cast(&json!("TRUE"), ValueType::Bool));
+        assert_eq!(
+            Some(json!("2020-05-30T09:51:27.506627539Z")),
+            cast(&json!("2020-05-30T09:51:27.506627539Z"), ValueType::Timestamp)
+        );
+        assert_eq!(None, cast(&json!("GET"), ValueType::Int));
+    }
+}